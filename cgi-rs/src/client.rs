@@ -1,16 +1,21 @@
+use std::collections::HashMap;
 use std::env;
 use std::io;
 use std::pin::pin;
 use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 
 use bytes::Bytes;
-use futures::stream;
+use futures::future::AbortHandle;
+use futures::future::AbortRegistration;
+use futures::future::Abortable;
 use futures::StreamExt;
 use futures::TryStreamExt;
 use http_body_util::BodyStream;
-use http_body_util::StreamBody;
 use hyper::body::Body;
 use hyper::body::Frame;
 use hyper::header;
@@ -23,12 +28,29 @@ use log::debug;
 use log::info;
 use tokio::io::stdin;
 use tokio::io::stdout;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 use tokio::io::BufWriter;
 use tokio::io::Stdin;
+use tokio::io::Take;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tokio_util::io::ReaderStream;
 
 use crate::common::ConnInfo;
+pub use crate::fastcgi::FastCgiBody;
+use crate::fastcgi::{
+    decode_pairs, decode_params, encode_pairs, end_request, parse_begin_request, read_record,
+    write_record, ABORT_REQUEST, BEGIN_REQUEST, END_REQUEST, GET_VALUES, GET_VALUES_RESULT,
+    KEEP_CONN, PARAMS, REQUEST_COMPLETE, RESPONDER, STDIN, STDOUT, UNKNOWN_ROLE, UNKNOWN_TYPE,
+};
+use crate::scgi::read_header;
 
 pub struct StdinBody {
     body: ReaderStream<Stdin>,
@@ -68,30 +90,554 @@ where
     ResBody::Data: AsRef<[u8]>,
 {
     info!("Process new GCI request");
+    let (req, conn_info) = build_request(|name| env::var(name), env::vars(), StdinBody::new())
+        .unwrap_or_else(|msg| panic!("{}", msg));
+
+    let service = service_builder(conn_info);
+
+    match service.call(req).await {
+        Ok(response) => write_response(&mut BufWriter::new(stdout()), response)
+            .await
+            .expect("Cannot write to stdout"),
+        Err(_) => {
+            panic!("cannot call service")
+        }
+    }
+}
+
+/// Socket accepting the connections of the web server.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+type ConnReader = Box<dyn AsyncRead + Send + Unpin>;
+type ConnWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+impl Listener {
+    /// Listening socket given by the web server as FCGI_LISTENSOCK_FILENO
+    /// (file descriptor 0), either a Unix or a TCP socket
+    #[cfg(unix)]
+    pub fn inherited() -> io::Result<Listener> {
+        use std::os::fd::{FromRawFd, IntoRawFd};
+
+        // SAFETY: the web server gives a listening socket as fd 0, owned by
+        // the returned listener
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(0) };
+        listener.set_nonblocking(true)?;
+        if listener.local_addr().is_ok() {
+            return Ok(Listener::Unix(UnixListener::from_std(listener)?));
+        }
+        // SAFETY: fd 0 is not a Unix socket, ownership is moved to the TCP
+        // listener
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(listener.into_raw_fd()) };
+        Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+    }
+
+    async fn accept(&self) -> io::Result<(ConnReader, ConnWriter)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/// Serves FastCGI requests from the connections of listener, until it
+/// fails. Requests of a connection are multiplexed, a service is built for
+/// each one.
+pub async fn run_fastcgi<S, F, ResBody>(listener: Listener, service_builder: F) -> io::Result<()>
+where
+    S: Service<Request<FastCgiBody>, Response = Response<ResBody>> + Send + 'static,
+    S::Future: Send,
+    F: Fn(ConnInfo) -> S + Send + Sync + 'static,
+    ResBody: Body + Send + 'static,
+    ResBody::Data: AsRef<[u8]> + Send,
+    ResBody::Error: Send,
+{
+    info!("Serve FastCGI requests");
+    let service_builder = Arc::new(service_builder);
+    loop {
+        let (reader, writer) = listener.accept().await?;
+        tokio::spawn(serve_fastcgi(reader, writer, service_builder.clone()));
+    }
+}
+
+/// Bytes of stdin records buffered for a FastCGI request until its body
+/// reads them. The request is ended once they are exceeded
+pub const MAX_FASTCGI_REQUEST_STDIN: usize = 1024 * 1024;
+
+/// Bytes of stdin records buffered for all the requests of a FastCGI
+/// connection. The request of the record exceeding them is ended
+pub const MAX_FASTCGI_CONNECTION_STDIN: usize = 8 * 1024 * 1024;
+
+/// FastCGI request of a connection, kept until it is answered
+struct FastCgiRequest {
+    keep_conn: bool,
+    params: Vec<u8>,
+    /// Buffer of the stdin records, never blocking the records of the other
+    /// requests of the connection
+    stdin: Option<mpsc::UnboundedSender<BufferedStdin>>,
+    /// Bytes in the stdin buffer
+    buffered: Arc<AtomicUsize>,
+    /// Task answering the request, once its params are read
+    task: Option<(JoinHandle<()>, AbortHandle)>,
+}
+
+impl FastCgiRequest {
+    fn is_answered(&self) -> bool {
+        self.task
+            .as_ref()
+            .is_some_and(|(task, _)| task.is_finished())
+    }
+
+    /// Whether a stdin record of len bytes fits in the buffers of the request
+    /// and of its connection
+    fn can_buffer(&self, len: usize, connection: &AtomicUsize) -> bool {
+        self.buffered.load(Ordering::Relaxed) + len <= MAX_FASTCGI_REQUEST_STDIN
+            && connection.load(Ordering::Relaxed) + len <= MAX_FASTCGI_CONNECTION_STDIN
+    }
+
+    /// Stops reading the request and cancels its service. Returns false if
+    /// the request has no task to answer it with END_REQUEST
+    fn cancel(&mut self) -> bool {
+        self.stdin = None;
+        match &self.task {
+            Some((_, respond)) => {
+                respond.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Stdin record counted in the buffered bytes of its request and connection
+/// until it is taken or dropped
+struct BufferedStdin {
+    content: Bytes,
+    len: usize,
+    request: Arc<AtomicUsize>,
+    connection: Arc<AtomicUsize>,
+}
+
+impl BufferedStdin {
+    fn new(content: Bytes, request: &Arc<AtomicUsize>, connection: &Arc<AtomicUsize>) -> Self {
+        let len = content.len();
+        request.fetch_add(len, Ordering::Relaxed);
+        connection.fetch_add(len, Ordering::Relaxed);
+        BufferedStdin {
+            content,
+            len,
+            request: request.clone(),
+            connection: connection.clone(),
+        }
+    }
+}
+
+impl Drop for BufferedStdin {
+    fn drop(&mut self) {
+        self.request.fetch_sub(self.len, Ordering::Relaxed);
+        self.connection.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+async fn serve_fastcgi<S, F, ResBody>(
+    mut reader: ConnReader,
+    writer: ConnWriter,
+    service_builder: Arc<F>,
+) where
+    S: Service<Request<FastCgiBody>, Response = Response<ResBody>> + Send + 'static,
+    S::Future: Send,
+    F: Fn(ConnInfo) -> S + Send + Sync + 'static,
+    ResBody: Body + Send + 'static,
+    ResBody::Data: AsRef<[u8]> + Send,
+    ResBody::Error: Send,
+{
+    let writer = Arc::new(Mutex::new(writer));
+    let mut requests: HashMap<u16, FastCgiRequest> = HashMap::new();
+    let buffered = Arc::new(AtomicUsize::new(0));
+    loop {
+        let record = match read_record(&mut reader).await {
+            Ok(Some(record)) => record,
+            Ok(None) => return,
+            Err(err) => {
+                debug!("Cannot read FastCGI record: {}", err);
+                return;
+            }
+        };
+        let request_id = record.request_id;
+        let written = match record.kind {
+            BEGIN_REQUEST => match parse_begin_request(&record.content) {
+                Ok((RESPONDER, flags)) => {
+                    requests.retain(|_, request| !request.is_answered());
+                    let request = FastCgiRequest {
+                        keep_conn: flags & KEEP_CONN != 0,
+                        params: Vec::new(),
+                        stdin: None,
+                        buffered: Arc::new(AtomicUsize::new(0)),
+                        task: None,
+                    };
+                    requests.insert(request_id, request);
+                    Ok(())
+                }
+                Ok(_) => {
+                    let content = end_request(0, UNKNOWN_ROLE);
+                    let mut writer = writer.lock().await;
+                    write_record(&mut *writer, END_REQUEST, request_id, &content).await
+                }
+                Err(err) => Err(err),
+            },
+            PARAMS => {
+                if let Some(request) = requests.get_mut(&request_id) {
+                    if record.content.is_empty() {
+                        let (sender, receiver) = mpsc::unbounded_channel();
+                        let (respond, registration) = AbortHandle::new_pair();
+                        request.stdin = Some(sender);
+                        let task = tokio::spawn(respond_fastcgi(
+                            std::mem::take(&mut request.params),
+                            receiver,
+                            request_id,
+                            request.keep_conn,
+                            registration,
+                            writer.clone(),
+                            service_builder.clone(),
+                        ));
+                        request.task = Some((task, respond));
+                    } else {
+                        request.params.extend_from_slice(&record.content);
+                    }
+                }
+                Ok(())
+            }
+            STDIN => match requests.get_mut(&request_id) {
+                Some(request) if record.content.is_empty() => {
+                    request.stdin = None;
+                    Ok(())
+                }
+                Some(request) => match &request.stdin {
+                    Some(stdin) if request.can_buffer(record.content.len(), &buffered) => {
+                        // The body may have been dropped, or be empty
+                        let content =
+                            BufferedStdin::new(record.content, &request.buffered, &buffered);
+                        let _ = stdin.send(content);
+                        Ok(())
+                    }
+                    // The task answering the request ends it
+                    Some(_) => {
+                        debug!("Too much stdin buffered for FastCGI request {}", request_id);
+                        request.cancel();
+                        Ok(())
+                    }
+                    None => Ok(()),
+                },
+                None => Ok(()),
+            },
+            // An answered request is not aborted again
+            ABORT_REQUEST => match requests.remove(&request_id) {
+                Some(mut request) if !request.is_answered() => {
+                    if request.cancel() {
+                        Ok(())
+                    } else {
+                        let content = end_request(0, REQUEST_COMPLETE);
+                        let mut writer = writer.lock().await;
+                        write_record(&mut *writer, END_REQUEST, request_id, &content).await
+                    }
+                }
+                _ => Ok(()),
+            },
+            GET_VALUES => match decode_pairs(&record.content) {
+                Ok(names) => {
+                    let values = names
+                        .iter()
+                        .filter(|(name, _)| name == b"FCGI_MPXS_CONNS")
+                        .map(|(name, _)| (name.as_slice(), b"1".as_slice()));
+                    let content = encode_pairs(values);
+                    let mut writer = writer.lock().await;
+                    write_record(&mut *writer, GET_VALUES_RESULT, 0, &content).await
+                }
+                Err(err) => Err(err),
+            },
+            kind if request_id == 0 => {
+                let mut writer = writer.lock().await;
+                write_record(&mut *writer, UNKNOWN_TYPE, 0, &[kind, 0, 0, 0, 0, 0, 0, 0]).await
+            }
+            _ => Ok(()),
+        };
+        if let Err(err) = written {
+            debug!("FastCGI connection failed: {}", err);
+            return;
+        }
+    }
+}
+
+/// Calls a service for a FastCGI request and writes its response as stdout
+/// records. Once the service is aborted, the request is ended after what it
+/// already wrote
+async fn respond_fastcgi<S, F, ResBody>(
+    params: Vec<u8>,
+    stdin: mpsc::UnboundedReceiver<BufferedStdin>,
+    request_id: u16,
+    keep_conn: bool,
+    registration: AbortRegistration,
+    writer: Arc<Mutex<ConnWriter>>,
+    service_builder: Arc<F>,
+) where
+    S: Service<Request<FastCgiBody>, Response = Response<ResBody>> + Send + 'static,
+    S::Future: Send,
+    F: Fn(ConnInfo) -> S + Send + Sync + 'static,
+    ResBody: Body + Send + 'static,
+    ResBody::Data: AsRef<[u8]> + Send,
+    ResBody::Error: Send,
+{
+    let (mut output, mut output_reader) = tokio::io::duplex(16 * 1024);
+    let respond = async move {
+        let request = decode_params(&params)
+            .map_err(|err| err.to_string())
+            .and_then(|params| {
+                let var = |name: &str| {
+                    params
+                        .iter()
+                        .find(|(k, _)| k == name)
+                        .map(|(_, v)| v.clone())
+                        .ok_or(env::VarError::NotPresent)
+                };
+                let content_length = var("CONTENT_LENGTH")
+                    .ok()
+                    .and_then(|length| length.trim().parse::<u64>().ok())
+                    .unwrap_or(0);
+                let (sender, receiver) = mpsc::channel(16);
+                // The stdin records of an empty body are dropped
+                if content_length > 0 {
+                    tokio::spawn(forward_stdin(stdin, sender));
+                }
+                let body = FastCgiBody::new(receiver, content_length == 0);
+                build_request(var, params.clone(), body)
+            });
+        let written = match request {
+            Ok((req, conn_info)) => match service_builder(conn_info).call(req).await.ok() {
+                Some(response) => write_response(&mut output, response).await,
+                None => {
+                    debug!("cannot call service");
+                    output
+                        .write_all(b"Status: 500 Internal Server Error\r\n\r\n")
+                        .await
+                }
+            },
+            Err(msg) => {
+                debug!("Invalid FastCGI request: {}", msg);
+                output.write_all(b"Status: 400 Bad Request\r\n\r\n").await
+            }
+        };
+        if let Err(err) = written {
+            debug!("Cannot write FastCGI response: {}", err);
+        }
+    };
+    let respond = Abortable::new(respond, registration);
+    // The reader is dropped if the connection fails, so that writing the
+    // response fails instead of waiting for room in output
+    let forward = async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let n = output_reader.read(&mut buf).await?;
+            let mut writer = writer.lock().await;
+            write_record(&mut *writer, STDOUT, request_id, &buf[..n]).await?;
+            if n == 0 {
+                let content = end_request(0, REQUEST_COMPLETE);
+                write_record(&mut *writer, END_REQUEST, request_id, &content).await?;
+                writer.flush().await?;
+                if !keep_conn {
+                    writer.shutdown().await?;
+                }
+                return Ok::<_, io::Error>(());
+            }
+            writer.flush().await?;
+        }
+    };
+    let (_, forwarded) = tokio::join!(respond, forward);
+    if let Err(err) = forwarded {
+        debug!("Cannot write FastCGI response: {}", err);
+    }
+}
+
+/// Sends the buffered stdin records of a request to its body, as the body
+/// is read. Records are dropped once the body is dropped
+async fn forward_stdin(
+    mut stdin: mpsc::UnboundedReceiver<BufferedStdin>,
+    body: mpsc::Sender<io::Result<Bytes>>,
+) {
+    while let Some(mut record) = stdin.recv().await {
+        let content = std::mem::take(&mut record.content);
+        drop(record);
+        if body.send(Ok(content)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Body of an SCGI request, read from its connection.
+pub struct ScgiBody {
+    body: ReaderStream<Take<ConnReader>>,
+}
+
+impl Body for ScgiBody {
+    type Data = Bytes;
+
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.body.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(bytes))) => Poll::Ready(Some(Ok(Frame::data(bytes)))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Serves SCGI requests from the connections of listener, until it fails.
+/// A service is built for each request.
+pub async fn run_scgi<S, F, ResBody>(listener: Listener, service_builder: F) -> io::Result<()>
+where
+    S: Service<Request<ScgiBody>, Response = Response<ResBody>> + Send + 'static,
+    S::Future: Send,
+    F: Fn(ConnInfo) -> S + Send + Sync + 'static,
+    ResBody: Body + Send + 'static,
+    ResBody::Data: AsRef<[u8]> + Send,
+    ResBody::Error: Send,
+{
+    info!("Serve SCGI requests");
+    let service_builder = Arc::new(service_builder);
+    loop {
+        let (reader, writer) = listener.accept().await?;
+        let service_builder = service_builder.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_scgi(reader, writer, service_builder).await {
+                debug!("Cannot serve SCGI request: {}", err);
+            }
+        });
+    }
+}
+
+async fn serve_scgi<S, F, ResBody>(
+    mut reader: ConnReader,
+    writer: ConnWriter,
+    service_builder: Arc<F>,
+) -> io::Result<()>
+where
+    S: Service<Request<ScgiBody>, Response = Response<ResBody>>,
+    F: Fn(ConnInfo) -> S,
+    ResBody: Body,
+    ResBody::Data: AsRef<[u8]>,
+{
+    let mut out = BufWriter::new(writer);
+    let request = match read_header(&mut reader).await {
+        Ok(headers) => {
+            let var = |name: &str| {
+                headers
+                    .iter()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.clone())
+                    .ok_or(env::VarError::NotPresent)
+            };
+            let content_length = var("CONTENT_LENGTH")
+                .ok()
+                .and_then(|length| length.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            let body = ScgiBody {
+                body: ReaderStream::new(reader.take(content_length)),
+            };
+            build_request(var, headers.clone(), body)
+        }
+        Err(err) => Err(err.to_string()),
+    };
+    match request {
+        Ok((req, conn_info)) => match service_builder(conn_info).call(req).await.ok() {
+            Some(response) => write_response(&mut out, response).await?,
+            None => {
+                debug!("cannot call service");
+                out.write_all(b"Status: 500 Internal Server Error\r\n\r\n")
+                    .await?
+            }
+        },
+        Err(msg) => {
+            debug!("Invalid SCGI request: {}", msg);
+            out.write_all(b"Status: 400 Bad Request\r\n\r\n").await?
+        }
+    }
+    out.flush().await?;
+    out.shutdown().await
+}
+
+/// Request and connection info of the CGI meta-variables read by var.
+/// vars are all the meta-variables, for the HTTP_ ones.
+fn build_request<V, B>(
+    var: V,
+    vars: impl IntoIterator<Item = (String, String)>,
+    body: B,
+) -> Result<(Request<B>, ConnInfo), String>
+where
+    V: Fn(&str) -> Result<String, env::VarError>,
+{
     let mut req_builder = Request::builder();
 
-    req_builder = req_builder.method::<&str>(
-        &env::var("REQUEST_METHOD").expect("Environment variable REQUEST_METHOD is not defined"),
-    );
+    req_builder = req_builder.method::<&str>(&var("REQUEST_METHOD").map_err(|err| {
+        format!(
+            "Environment variable REQUEST_METHOD is not defined: {:?}",
+            err
+        )
+    })?);
 
     // Cannot create version from string
-    let _proto =
-        env::var("SERVER_PROTOCOL").expect("Environment variable SERVER_PROTOCOL is not defined");
+    let _proto = var("SERVER_PROTOCOL").map_err(|err| {
+        format!(
+            "Environment variable SERVER_PROTOCOL is not defined: {:?}",
+            err
+        )
+    })?;
     req_builder = req_builder.version(Version::default());
 
-    match env::var("HTTP_HOST") {
+    match var("HTTP_HOST") {
         Ok(host) => {
             debug!("HTTP_HOST: {}", &host);
             req_builder = req_builder.header(header::HOST, host);
         }
         Err(env::VarError::NotUnicode(os_string)) => {
-            panic!("Cannot read {} as host value", os_string.to_string_lossy())
+            return Err(format!(
+                "Cannot read {} as host value",
+                os_string.to_string_lossy()
+            ))
         }
 
         Err(env::VarError::NotPresent) => {}
     }
 
-    match env::var("CONTENT_LENGTH") {
+    match var("CONTENT_LENGTH") {
         Ok(length) => {
             debug!("CONTENT_LENGTH: {}", &length);
             if !length.trim().is_empty() {
@@ -99,129 +645,161 @@ where
                     Ok(_) => {
                         req_builder = req_builder.header(header::CONTENT_LENGTH, length);
                     }
-                    Err(_) => panic!("Cannot read {} as content-length integer value", length),
+                    Err(_) => {
+                        return Err(format!(
+                            "Cannot read {} as content-length integer value",
+                            length
+                        ))
+                    }
                 }
             }
         }
         Err(env::VarError::NotPresent) => {}
-        Err(env::VarError::NotUnicode(os_string)) => panic!(
-            "Cannot read {} as content-length value",
-            os_string.to_string_lossy()
-        ),
+        Err(env::VarError::NotUnicode(os_string)) => {
+            return Err(format!(
+                "Cannot read {} as content-length value",
+                os_string.to_string_lossy()
+            ))
+        }
     }
 
-    match env::var("CONTENT_TYPE") {
+    match var("CONTENT_TYPE") {
         Ok(ct) => {
             debug!("CONTENT_TYPE: {}", &ct);
             req_builder = req_builder.header(header::CONTENT_TYPE, ct);
         }
         Err(env::VarError::NotPresent) => {}
-        Err(env::VarError::NotUnicode(os_string)) => panic!(
-            "Cannot read {} as content-type value",
-            os_string.to_string_lossy()
-        ),
+        Err(env::VarError::NotUnicode(os_string)) => {
+            return Err(format!(
+                "Cannot read {} as content-type value",
+                os_string.to_string_lossy()
+            ))
+        }
     }
 
-    for (k, v) in env::vars() {
+    for (k, v) in vars {
         debug!("ENV => {}: {}", &k, &v);
-        if k.starts_with("HTTP_") {
-            req_builder = req_builder.header(&k[5..].replace("_", "-"), v);
+        if let Some(name) = k.strip_prefix("HTTP_") {
+            req_builder = req_builder.header(name.replace('_', "-"), v);
         }
     }
 
-    match env::var("REQUEST_URI") {
+    match var("REQUEST_URI") {
         Ok(request_uri) => {
             debug!("REQUEST_URI: {}", &request_uri);
             match Uri::try_from(&request_uri) {
                 Ok(uri) => req_builder = req_builder.uri(uri),
-                Err(_) => panic!("Cannot read REQUEST_URI ({}) as valid URI", &request_uri),
+                Err(_) => {
+                    return Err(format!(
+                        "Cannot read REQUEST_URI ({}) as valid URI",
+                        &request_uri
+                    ))
+                }
+            }
+        }
+        Err(env::VarError::NotPresent) => {
+            let req_uri = get_req_uri(&var)?;
+            match Uri::try_from(&req_uri) {
+                Ok(uri) => req_builder = req_builder.uri(uri),
+                Err(_) => {
+                    return Err(format!(
+                        "Cannot read SCRIPT_NAME + PATH_INFO + ? + QUERY_STRING ({}) as valid URI",
+                        req_uri
+                    ))
+                }
             }
         }
-        Err(env::VarError::NotPresent) => match Uri::try_from(get_req_uri()) {
-            Ok(uri) => req_builder = req_builder.uri(uri),
-            Err(_) => panic!(
-                "Cannot read SCRIPT_NAME + PATH_INFO + ? + QUERY_STRING ({}) as valid URI",
-                get_req_uri()
-            ),
-        },
         Err(env::VarError::NotUnicode(os_string)) => {
-            panic!("Cannot read {} as URI value", os_string.to_string_lossy())
+            return Err(format!(
+                "Cannot read {} as URI value",
+                os_string.to_string_lossy()
+            ))
         }
     }
 
-    let req = req_builder.body(StdinBody::new()).unwrap();
+    let req = req_builder.body(body).map_err(|err| err.to_string())?;
 
     let conn_info = ConnInfo {
-        local_addr: match env::var("SERVER_NAME") {
+        local_addr: match var("SERVER_NAME") {
             Ok(name) => name,
-            Err(env::VarError::NotPresent) => panic!("Missing variable SERVER_NAME"),
+            Err(env::VarError::NotPresent) => return Err("Missing variable SERVER_NAME".into()),
             Err(env::VarError::NotUnicode(os_string)) => {
-                panic!("Cannot read {} as server name", os_string.to_string_lossy())
+                return Err(format!(
+                    "Cannot read {} as server name",
+                    os_string.to_string_lossy()
+                ))
             }
         },
-        remote_addr: match env::var("REMOTE_ADDR") {
+        remote_addr: match var("REMOTE_ADDR") {
             Ok(addr) => match addr.parse() {
                 Ok(addr) => addr,
-                Err(_) => panic!("Cannot parse {} as IP address", addr),
+                Err(_) => return Err(format!("Cannot parse {} as IP address", addr)),
             },
-            Err(env::VarError::NotPresent) => panic!("Missing variable REMOTE_ADDR"),
-            Err(env::VarError::NotUnicode(os_string)) => panic!(
-                "Cannot read {} as remote IP address",
-                os_string.to_string_lossy()
-            ),
+            Err(env::VarError::NotPresent) => return Err("Missing variable REMOTE_ADDR".into()),
+            Err(env::VarError::NotUnicode(os_string)) => {
+                return Err(format!(
+                    "Cannot read {} as remote IP address",
+                    os_string.to_string_lossy()
+                ))
+            }
         },
-        remote_port: match env::var("REMOTE_PORT") {
+        remote_port: match var("REMOTE_PORT") {
             Ok(port) => match port.parse() {
                 Ok(port) => port,
-                Err(_) => panic!("Cannot parse {} as remote port", port),
+                Err(_) => return Err(format!("Cannot parse {} as remote port", port)),
             },
-            Err(env::VarError::NotPresent) => panic!("Missing variable REMOTE_PORT"),
+            Err(env::VarError::NotPresent) => return Err("Missing variable REMOTE_PORT".into()),
             Err(env::VarError::NotUnicode(os_string)) => {
-                panic!("Cannot read {} as remote port", os_string.to_string_lossy())
+                return Err(format!(
+                    "Cannot read {} as remote port",
+                    os_string.to_string_lossy()
+                ))
             }
         },
-        local_port: match env::var("SERVER_PORT") {
+        local_port: match var("SERVER_PORT") {
             Ok(port) => match port.parse() {
                 Ok(port) => port,
-                Err(_) => panic!("Cannot parse {} as local port", port),
+                Err(_) => return Err(format!("Cannot parse {} as local port", port)),
             },
-            Err(env::VarError::NotPresent) => panic!("Missing variable SERVER_PORT"),
+            Err(env::VarError::NotPresent) => return Err("Missing variable SERVER_PORT".into()),
             Err(env::VarError::NotUnicode(os_string)) => {
-                panic!("Cannot read {} as local port", os_string.to_string_lossy())
+                return Err(format!(
+                    "Cannot read {} as local port",
+                    os_string.to_string_lossy()
+                ))
             }
         },
     };
 
-    let service = service_builder(conn_info);
-
-    match service.call(req).await {
-        Ok(response) => write_response(response)
-            .await
-            .expect("Cannot write to stdout"),
-        Err(err) => {
-            panic!("cannot call service")
-        }
-    }
+    Ok((req, conn_info))
 }
 
-fn get_req_uri() -> String {
-    let res = env::var("SCRIPT_NAME").unwrap_or_default()
-        + &env::var("PATH_INFO").unwrap_or_default()
-        + &match env::var("QUERY_STRING") {
+fn get_req_uri<V>(var: V) -> Result<String, String>
+where
+    V: Fn(&str) -> Result<String, env::VarError>,
+{
+    let res = var("SCRIPT_NAME").unwrap_or_default()
+        + &var("PATH_INFO").unwrap_or_default()
+        + &match var("QUERY_STRING") {
             Ok(query) => "?".to_string() + &query,
             Err(env::VarError::NotPresent) => "".to_string(),
             Err(env::VarError::NotUnicode(os_string)) => {
-                panic!("Cannot read {} as query value", os_string.to_string_lossy())
+                return Err(format!(
+                    "Cannot read {} as query value",
+                    os_string.to_string_lossy()
+                ))
             }
         };
     debug!("get_req_uri(): {}", &res);
-    return res;
+    Ok(res)
 }
 
-async fn write_response<Data: AsRef<[u8]>, B: Body<Data = Data>>(
-    response: Response<B>,
-) -> io::Result<()> {
-    let mut out = BufWriter::new(stdout());
+async fn write_response<W, Data, B>(out: &mut W, response: Response<B>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    Data: AsRef<[u8]>,
+    B: Body<Data = Data>,
+{
     let code = response.status().as_u16();
     debug!("STATUS: {}", code);
     let reason = response.status().canonical_reason();
@@ -248,12 +826,9 @@ async fn write_response<Data: AsRef<[u8]>, B: Body<Data = Data>>(
     let body = pin!(response.into_body());
     let mut stream_body = BodyStream::new(body);
     while let Ok(Some(frame)) = stream_body.try_next().await {
-        match frame.into_data() {
-            Ok(data) => {
-                out.write_all(data.as_ref()).await?;
-                out.flush().await?;
-            }
-            Err(_) => {}
+        if let Ok(data) = frame.into_data() {
+            out.write_all(data.as_ref()).await?;
+            out.flush().await?;
         }
     }
     Ok(())
@@ -0,0 +1,308 @@
+//! Records of the FastCGI protocol, version 1.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+pub(crate) const VERSION_1: u8 = 1;
+
+pub(crate) const BEGIN_REQUEST: u8 = 1;
+pub(crate) const ABORT_REQUEST: u8 = 2;
+pub(crate) const END_REQUEST: u8 = 3;
+pub(crate) const PARAMS: u8 = 4;
+pub(crate) const STDIN: u8 = 5;
+pub(crate) const STDOUT: u8 = 6;
+pub(crate) const STDERR: u8 = 7;
+pub(crate) const GET_VALUES: u8 = 9;
+pub(crate) const GET_VALUES_RESULT: u8 = 10;
+pub(crate) const UNKNOWN_TYPE: u8 = 11;
+
+pub(crate) const RESPONDER: u16 = 1;
+
+/// Flag of a begin request record keeping the connection open after the request
+pub(crate) const KEEP_CONN: u8 = 1;
+
+pub(crate) const REQUEST_COMPLETE: u8 = 0;
+pub(crate) const CANT_MPX_CONN: u8 = 1;
+pub(crate) const UNKNOWN_ROLE: u8 = 3;
+
+/// Max length of the content of a record
+pub(crate) const MAX_CONTENT: usize = u16::MAX as usize;
+
+/// Body of a FastCGI request, made of its stdin records.
+#[derive(Debug)]
+pub struct FastCgiBody {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    empty: bool,
+}
+
+impl FastCgiBody {
+    /// Body receiving the stdin records, empty bodies never poll receiver
+    pub(crate) fn new(receiver: mpsc::Receiver<io::Result<Bytes>>, empty: bool) -> Self {
+        FastCgiBody { receiver, empty }
+    }
+}
+
+impl Body for FastCgiBody {
+    type Data = Bytes;
+
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.empty {
+            return Poll::Ready(None);
+        }
+        self.receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.empty
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub(crate) kind: u8,
+    pub(crate) request_id: u16,
+    pub(crate) content: Bytes,
+}
+
+/// Reads a record. Returns None at the end of the stream.
+pub(crate) async fn read_record<R>(reader: &mut R) -> io::Result<Option<Record>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header[..1]).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    reader.read_exact(&mut header[1..]).await?;
+    if header[0] != VERSION_1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported FastCGI version {}", header[0]),
+        ));
+    }
+    let request_id = u16::from_be_bytes([header[2], header[3]]);
+    let content_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding_length = header[6] as usize;
+    let mut content = vec![0u8; content_length + padding_length];
+    reader.read_exact(&mut content).await?;
+    content.truncate(content_length);
+    Ok(Some(Record {
+        kind: header[1],
+        request_id,
+        content: Bytes::from(content),
+    }))
+}
+
+/// Writes a single record, content must not be longer than [`MAX_CONTENT`].
+pub(crate) async fn write_record<W>(
+    writer: &mut W,
+    kind: u8,
+    request_id: u16,
+    content: &[u8],
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    debug_assert!(content.len() <= MAX_CONTENT);
+    let padding_length = (8 - content.len() % 8) % 8;
+    let mut record = Vec::with_capacity(8 + content.len() + padding_length);
+    record.extend_from_slice(&[VERSION_1, kind]);
+    record.extend_from_slice(&request_id.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[padding_length as u8, 0]);
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding_length, 0);
+    writer.write_all(&record).await
+}
+
+/// Writes data of a stream record type, split in as many records as
+/// needed. Nothing is written if data is empty, the end of the stream is
+/// an empty record.
+pub(crate) async fn write_stream<W>(
+    writer: &mut W,
+    kind: u8,
+    request_id: u16,
+    data: &[u8],
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for chunk in data.chunks(MAX_CONTENT) {
+        write_record(writer, kind, request_id, chunk).await?;
+    }
+    Ok(())
+}
+
+pub(crate) fn begin_request(role: u16, flags: u8) -> [u8; 8] {
+    let role = role.to_be_bytes();
+    [role[0], role[1], flags, 0, 0, 0, 0, 0]
+}
+
+/// Role and flags of a begin request record
+pub(crate) fn parse_begin_request(content: &[u8]) -> io::Result<(u16, u8)> {
+    if content.len() < 3 {
+        return Err(invalid("Truncated FastCGI begin request"));
+    }
+    Ok((u16::from_be_bytes([content[0], content[1]]), content[2]))
+}
+
+pub(crate) fn end_request(app_status: u32, protocol_status: u8) -> [u8; 8] {
+    let app_status = app_status.to_be_bytes();
+    [
+        app_status[0],
+        app_status[1],
+        app_status[2],
+        app_status[3],
+        protocol_status,
+        0,
+        0,
+        0,
+    ]
+}
+
+/// Application status and protocol status of an end request record
+pub(crate) fn parse_end_request(content: &[u8]) -> io::Result<(u32, u8)> {
+    if content.len() < 5 {
+        return Err(invalid("Truncated FastCGI end request"));
+    }
+    Ok((
+        u32::from_be_bytes([content[0], content[1], content[2], content[3]]),
+        content[4],
+    ))
+}
+
+/// Encodes name-value pairs
+pub(crate) fn encode_pairs<'a, I>(pairs: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a [u8], &'a [u8])>,
+{
+    let mut encoded = Vec::new();
+    for (name, value) in pairs {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name);
+        encoded.extend_from_slice(value);
+    }
+    encoded
+}
+
+fn encode_length(encoded: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        encoded.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Decodes name-value pairs
+pub(crate) fn decode_pairs(mut data: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = Vec::new();
+    while !data.is_empty() {
+        let name_len = decode_length(&mut data)?;
+        let value_len = decode_length(&mut data)?;
+        if data.len() < name_len + value_len {
+            return Err(invalid("Truncated FastCGI name-value pair"));
+        }
+        let (name, rest) = data.split_at(name_len);
+        let (value, rest) = rest.split_at(value_len);
+        pairs.push((name.to_vec(), value.to_vec()));
+        data = rest;
+    }
+    Ok(pairs)
+}
+
+/// Decodes name-value pairs as strings, invalid UTF-8 is replaced
+pub(crate) fn decode_params(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    Ok(decode_pairs(data)?
+        .into_iter()
+        .map(|(name, value)| {
+            (
+                String::from_utf8_lossy(&name).to_string(),
+                String::from_utf8_lossy(&value).to_string(),
+            )
+        })
+        .collect())
+}
+
+fn decode_length(data: &mut &[u8]) -> io::Result<usize> {
+    match data.first() {
+        Some(len) if len & 0x80 == 0 => {
+            *data = &data[1..];
+            Ok(*len as usize)
+        }
+        Some(_) if data.len() >= 4 => {
+            let len = u32::from_be_bytes([data[0] & 0x7f, data[1], data[2], data[3]]);
+            *data = &data[4..];
+            Ok(len as usize)
+        }
+        _ => Err(invalid("Truncated FastCGI name-value pair")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_round_trip() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, STDOUT, 1, b"hello").await.unwrap();
+        assert_eq!(
+            buf,
+            [1, 6, 0, 1, 0, 5, 3, 0, b'h', b'e', b'l', b'l', b'o', 0, 0, 0]
+        );
+        let record = read_record(&mut buf.as_slice()).await.unwrap().unwrap();
+        assert_eq!(
+            record,
+            Record {
+                kind: STDOUT,
+                request_id: 1,
+                content: Bytes::from_static(b"hello"),
+            }
+        );
+        assert_eq!(read_record(&mut &b""[..]).await.unwrap(), None);
+    }
+
+    #[test]
+    fn encode_pairs_lengths() {
+        let long = [b'x'; 200];
+        let encoded = encode_pairs([
+            (&b"SCRIPT_NAME"[..], &b"/app"[..]),
+            (&b"LONG"[..], &long[..]),
+        ]);
+        assert_eq!(&encoded[..2], &[11, 4]);
+        assert_eq!(&encoded[17..22], &[4, 0x80, 0, 0, 200]);
+        assert_eq!(&encoded[26..], &long[..]);
+        assert_eq!(
+            decode_pairs(&encoded).unwrap(),
+            [
+                (b"SCRIPT_NAME".to_vec(), b"/app".to_vec()),
+                (b"LONG".to_vec(), long.to_vec()),
+            ]
+        );
+        assert!(decode_pairs(&encoded[..30]).is_err());
+    }
+}
@@ -1,4 +1,6 @@
 pub mod client;
+pub(crate) mod fastcgi;
+pub(crate) mod scgi;
 pub mod server;
 pub mod common;
 
@@ -0,0 +1,119 @@
+//! Request headers of the SCGI protocol.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Max length of the headers of a request
+pub(crate) const MAX_HEADER: usize = 1024 * 1024;
+
+/// Encodes the headers of a request as a netstring. CONTENT_LENGTH must be
+/// the first header, `SCGI: 1` is added after it.
+pub(crate) fn encode_header<'a, I>(content_length: &'a str, headers: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut content = Vec::new();
+    for (name, value) in [("CONTENT_LENGTH", content_length), ("SCGI", "1")]
+        .into_iter()
+        .chain(
+            headers
+                .into_iter()
+                .filter(|(name, _)| *name != "CONTENT_LENGTH" && *name != "SCGI"),
+        )
+    {
+        content.extend_from_slice(name.as_bytes());
+        content.push(0);
+        content.extend_from_slice(value.as_bytes());
+        content.push(0);
+    }
+    let mut encoded = format!("{}:", content.len()).into_bytes();
+    encoded.extend_from_slice(&content);
+    encoded.push(b',');
+    encoded
+}
+
+/// Reads the netstring of the headers of a request
+pub(crate) async fn read_header<R>(reader: &mut R) -> io::Result<Vec<(String, String)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len: usize = 0;
+    loop {
+        let c = reader.read_u8().await?;
+        match c {
+            b':' => break,
+            b'0'..=b'9' if len <= MAX_HEADER => len = len * 10 + (c - b'0') as usize,
+            _ => return Err(invalid("Invalid SCGI netstring length")),
+        }
+    }
+    if len > MAX_HEADER {
+        return Err(invalid("SCGI headers too long"));
+    }
+    let mut content = vec![0u8; len + 1];
+    reader.read_exact(&mut content).await?;
+    if content.pop() != Some(b',') {
+        return Err(invalid("Missing SCGI netstring end"));
+    }
+    if content.pop().is_some_and(|c| c != 0) {
+        return Err(invalid("Missing SCGI header end"));
+    }
+
+    let mut fields = content.split(|c| *c == 0);
+    let mut headers = Vec::new();
+    while let Some(name) = fields.next() {
+        let value = fields
+            .next()
+            .ok_or_else(|| invalid("Missing SCGI header value"))?;
+        headers.push((
+            String::from_utf8_lossy(name).to_string(),
+            String::from_utf8_lossy(value).to_string(),
+        ));
+    }
+    Ok(headers)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_header_netstring() {
+        let encoded = encode_header("0", [("REQUEST_METHOD", "GET"), ("EMPTY", "")]);
+        let reader = [encoded.as_slice(), b"body"].concat();
+        let mut reader = reader.as_slice();
+        let headers = read_header(&mut reader).await.unwrap();
+        assert_eq!(
+            headers,
+            [
+                ("CONTENT_LENGTH".to_string(), "0".to_string()),
+                ("SCGI".to_string(), "1".to_string()),
+                ("REQUEST_METHOD".to_string(), "GET".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+            ]
+        );
+        assert_eq!(reader, b"body");
+        assert!(read_header(&mut &b"4:abc\0;"[..]).await.is_err());
+        assert!(read_header(&mut &b"x:"[..]).await.is_err());
+    }
+
+    #[test]
+    fn encode_header_netstring() {
+        let encoded = encode_header(
+            "5",
+            [
+                ("REQUEST_METHOD", "POST"),
+                ("CONTENT_LENGTH", "5"),
+                ("SCGI", "1"),
+            ],
+        );
+        assert_eq!(
+            encoded,
+            b"44:CONTENT_LENGTH\x005\x00SCGI\x001\x00REQUEST_METHOD\x00POST\x00,"
+        );
+    }
+}
@@ -2,43 +2,94 @@ use std::{
     borrow::Cow,
     collections::HashMap,
     convert::Infallible,
-    future::ready,
+    future::{ready, Future},
     net::SocketAddr,
     ops::Deref,
     path::{Path, PathBuf},
-    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use bytes::Bytes;
-use hershell::process::{self, ProcStreamExt};
-use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, Full, StreamBody};
+use http_body_util::{
+    combinators::{BoxBody, UnsyncBoxBody},
+    BodyExt, BodyStream, Empty, Full, StreamBody,
+};
 use hyper::{
     body::{Body, Frame},
-    header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING},
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING},
     Request, Response, StatusCode,
 };
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
     process::Command,
+    time::{sleep_until, timeout_at, Instant},
 };
 
-use futures::TryStreamExt;
-use tokio_util::io::{ReaderStream, StreamReader};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use log::trace;
+use tokio_util::io::ReaderStream;
+
+mod alias;
+mod backend;
+mod error;
+mod fastcgi;
+mod interpreter;
+mod limits;
+mod process;
+mod redirect;
+mod response;
+mod router;
+mod sandbox;
+mod scgi;
+mod service;
+mod spool;
+mod stderr;
+mod suexec;
+mod wrap;
+
+pub use crate::fastcgi::FastCgiBody;
+pub use alias::ScriptAlias;
+pub use backend::BackendAddress;
+pub use error::{CgiServerError, ErrorHandler};
+pub use fastcgi::{FastCgiBackend, FastCgiPool};
+pub use interpreter::Interpreter;
+pub use limits::{IoPriority, ResourceLimits};
+use process::{spawn_script, KillGuard, ScriptProcess, ScriptStderr, ScriptStdin};
+pub use process::{ProcessInfo, DEFAULT_KILL_GRACE};
+use redirect::{is_local_location, rewrite_request};
+pub use redirect::{
+    LocalRedirect, RedirectFuture, RedirectInfo, RedirectService, MAX_LOCAL_REDIRECTS,
+};
+use response::parse_nph_response;
+pub use response::{parse_cgi_response, CgiParseError, CgiResponseHead, ParseConfig, ParseMode};
+pub use router::{Route, RouteMatch, ScriptRouter};
+pub use sandbox::Sandbox;
+pub use scgi::ScgiBackend;
+pub use service::{ClonableStderr, ConnectInfo, ScriptFuture, ScriptService};
+pub use spool::SpoolConfig;
+use spool::SpoolError;
+pub use stderr::{
+    FileSink, LogSink, RingBufferSink, StderrLine, StderrMeta, StderrSink, FILE_SINK_CAPACITY,
+    MAX_STDERR_LINE,
+};
+pub use suexec::RunAs;
+pub use wrap::{serve_fastcgi_connection, FastCgiParams, FastCgiWrap};
 
-#[cfg(debug_assertions)]
-macro_rules! trace {
-    ($x:expr) => {
-        println!("{}", $x)
-    };
-}
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-#[cfg(not(debug_assertions))]
-macro_rules! trace {
-    ($x:expr) => {};
-}
+const X_REQUEST_ID: &str = "x-request-id";
 
-pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+/// Time waited for the exit status of a script whose output ends before
+/// its header block
+const HEADER_EXIT_WAIT: Duration = Duration::from_secs(1);
+
+/// Id of the requests without `X-Request-Id` header
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Debug, Clone)]
 pub struct Script {
@@ -61,9 +112,97 @@ pub struct Script {
 
     /// Inherited environment variables
     pub inherited_env: Vec<String>,
+
+    /// Spooling of request bodies without Content-Length.
+    /// If None, chunked requests are rejected
+    pub spool: Option<SpoolConfig>,
+
+    /// Whether the script is a non-parsed-header script
+    pub nph: NphMode,
+
+    /// Handler of local redirect responses.
+    /// If None, local redirects are sent to the client as 302
+    pub local_redirect: Option<LocalRedirect>,
+
+    /// Limits and strictness of the response header parser
+    pub parse_config: ParseConfig,
+
+    /// Hook called with the errors met while serving a request.
+    /// If None, errors are rendered as plain text responses
+    pub error_handler: Option<ErrorHandler>,
+
+    /// Destination of the stderr lines of the script.
+    /// If None, stderr is copied as is to the error writer
+    pub stderr_sink: Option<Arc<dyn StderrSink>>,
+
+    /// Interpreters by file extension, without the leading dot.
+    /// If the extension of path has none, path is run directly
+    pub interpreters: HashMap<String, Interpreter>,
+
+    /// Resource limits and priority of the script process.
+    /// If None, they are inherited from the server
+    pub rlimits: Option<ResourceLimits>,
+
+    /// User running the script, after suEXEC-like checks.
+    /// If None, the script runs as the server user
+    pub run_as: Option<RunAs>,
+
+    /// Landlock and seccomp sandbox of the script, only supported on Linux.
+    /// If None, the script is not sandboxed
+    pub sandbox: Option<Sandbox>,
+
+    /// Time given to a cancelled script to exit after SIGTERM, before its
+    /// process group gets SIGKILL. If None, [`DEFAULT_KILL_GRACE`]
+    pub kill_grace: Option<Duration>,
+
+    /// Time given to the script to send its header block once spawned.
+    /// When exceeded, the script is terminated and the response is 504.
+    /// If None, the script can take as long as it needs. Not used by
+    /// backends
+    pub header_timeout: Option<Duration>,
+
+    /// Wall-clock deadline of the whole request, local redirects included,
+    /// until the end of the response body. When exceeded while the body is
+    /// spooled the response is 408, otherwise the script is terminated: the
+    /// response is 504 before the header block, the body fails after. If
+    /// None, requests have no deadline. Not used by backends
+    pub request_timeout: Option<Duration>,
+}
+
+/// Non-parsed-header mode of a script.
+///
+/// NPH scripts write the HTTP status line and headers themselves, they are
+/// relayed to the client without being interpreted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NphMode {
+    /// NPH if the file name of the script starts with `nph-`
+    #[default]
+    Auto,
+    /// Always NPH
+    Enabled,
+    /// Never NPH
+    Disabled,
 }
 
 impl Script {
+    /// Interpreter of the script, picked by the extension of its path
+    pub fn interpreter(&self) -> Option<&Interpreter> {
+        let extension = self.path.extension()?.to_str()?;
+        self.interpreters.get(extension)
+    }
+
+    /// Tells whether the output of the script is relayed as is
+    pub fn is_nph(&self) -> bool {
+        match self.nph {
+            NphMode::Enabled => true,
+            NphMode::Disabled => false,
+            NphMode::Auto => self
+                .path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("nph-")),
+        }
+    }
+
     pub async fn serve<B, W>(
         &self,
         req: Request<B>,
@@ -71,31 +210,320 @@ impl Script {
         error_writer: W,
     ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>
     where
-        B: Body<Data = Bytes> + Send + Sync + Unpin + 'static,
+        B: Body<Data = Bytes> + Send + Unpin + 'static,
         <B as Body>::Error: Into<BoxError> + Sync + Send,
         W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
     {
-        let root_cow = self.root.to_string_lossy();
-        let root = if root_cow == "" {
-            Cow::from("/")
+        let mut req = req.map(|body| body.map_err(Into::into).boxed_unsync());
+        let deadline = self
+            .request_timeout
+            .map(|request_timeout| Instant::now() + request_timeout);
+        loop {
+            match self
+                .serve_once(req, remote, error_writer.clone(), deadline)
+                .await
+            {
+                Outcome::Response(response) => return Ok(response),
+                Outcome::LocalRedirect(redirect_req) => match &self.local_redirect {
+                    Some(LocalRedirect::Service(service)) => {
+                        return Ok(service.call(redirect_req, remote).await)
+                    }
+                    Some(LocalRedirect::SameScript) | None => {
+                        req = redirect_req.map(|body| body.map_err(Into::into).boxed_unsync())
+                    }
+                },
+            }
+        }
+    }
+
+    async fn serve_once<W>(
+        &self,
+        req: Request<UnsyncBoxBody<Bytes, BoxError>>,
+        remote: SocketAddr,
+        error_writer: W,
+        deadline: Option<Instant>,
+    ) -> Outcome
+    where
+        W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
+    {
+        let redirect_info = req.extensions().get::<RedirectInfo>().cloned();
+        let request_id = request_id(&req);
+
+        let mut env = self.build_env(&req, remote);
+        let interpreter = self.interpreter();
+
+        let cwd_cow: Cow<str>;
+
+        if let Some(dir) = &self.dir {
+            cwd_cow = dir.to_string_lossy();
         } else {
-            root_cow
+            let p = Path::new(&self.path);
+            if let Some(parent) = p.parent() {
+                let parent_path = parent.to_string_lossy();
+                if parent_path.is_empty() {
+                    cwd_cow = Cow::from(".")
+                } else {
+                    cwd_cow = parent_path;
+                }
+            } else {
+                cwd_cow = Cow::from(".");
+            }
+        }
+
+        let cwd: &str = &cwd_cow;
+
+        // Needed to build the rewritten request of a local redirect
+        let req_uri = req.uri().clone();
+        let req_headers = match self.local_redirect {
+            Some(_) => req.headers().clone(),
+            None => HeaderMap::new(),
         };
 
-        if let Some(encoding) = req.headers().get(TRANSFER_ENCODING) {
-            if encoding == "chunked" {
-                return Ok(get_error_response(
-                    StatusCode::BAD_REQUEST,
-                    "Chunked encoding is not supported by CGI.".to_string(),
-                ));
+        // The checked script is run by its canonical path
+        let path = match &self.run_as {
+            Some(run_as) => match run_as.check(&self.path).await {
+                Ok(canonical) => Cow::Owned(canonical),
+                Err(err) => return Outcome::Response(self.error_response(err)),
+            },
+            None => Cow::Borrowed(self.path.as_path()),
+        };
+
+        // Spooling a slow body counts in the deadline of the request
+        let stdin = match within(deadline, self.script_stdin(req, &mut env)).await {
+            Some(Ok(stdin)) => stdin,
+            Some(Err(err)) => return Outcome::Response(self.error_response(err)),
+            None => {
+                let err = std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "request deadline reached while spooling",
+                );
+                let err = CgiServerError::RequestTimeout(Box::new(err));
+                return Outcome::Response(self.error_response(err));
+            }
+        };
+
+        let stderr = self.script_stderr(request_id, remote, error_writer);
+
+        let mut command = match interpreter {
+            Some(interpreter) => {
+                let mut command = Command::new(&interpreter.path);
+                command.args(&interpreter.args).arg(path.as_ref());
+                command
+            }
+            None => Command::new(path.as_ref()),
+        };
+        command.current_dir(cwd).args(&self.args).envs(env);
+        #[cfg(unix)]
+        if let Some(limits) = self.rlimits {
+            // SAFETY: apply only makes async-signal-safe system calls
+            unsafe {
+                command.pre_exec(move || limits.apply());
+            }
+        }
+        // After the limits, which may need the privileges of the server
+        #[cfg(unix)]
+        if let Some(run_as) = self.run_as.clone() {
+            // SAFETY: apply only makes async-signal-safe system calls
+            unsafe {
+                command.pre_exec(move || run_as.apply());
+            }
+        }
+        // Last, the filter may deny the system calls of the other steps
+        #[cfg(unix)]
+        if let Some(sandbox) = &self.sandbox {
+            let script_dir = self.path.parent().unwrap_or(Path::new("."));
+            match sandbox.prepare(&[script_dir, Path::new(cwd)]) {
+                // SAFETY: apply only makes async-signal-safe system calls
+                Ok(prepared) => unsafe {
+                    command.pre_exec(move || prepared.apply());
+                },
+                Err(err) => {
+                    return Outcome::Response(self.error_response(CgiServerError::Spawn {
+                        path: self.path.clone(),
+                        source: err,
+                    }));
+                }
             }
         }
 
-        let req_path = req.uri().path();
-        let path_info = if root != "/" && req_path.starts_with(root.deref()) {
-            &req_path[root.len()..]
+        let ScriptProcess {
+            stdout,
+            mut info,
+            guard,
+            mut body_error,
+        } = match spawn_script(
+            &mut command,
+            stdin,
+            stderr,
+            self.kill_grace.unwrap_or(DEFAULT_KILL_GRACE),
+        ) {
+            Ok(process) => process,
+            Err(err) => {
+                return Outcome::Response(self.error_response(CgiServerError::Spawn {
+                    path: interpreter.map_or(&self.path, |i| &i.path).clone(),
+                    source: err,
+                }));
+            }
+        };
+
+        let mut process_reader = BufReader::new(stdout);
+        let header_deadline = match (self.header_timeout, deadline) {
+            (Some(header_timeout), Some(deadline)) => {
+                Some(deadline.min(Instant::now() + header_timeout))
+            }
+            (Some(header_timeout), None) => Some(Instant::now() + header_timeout),
+            (None, deadline) => deadline,
+        };
+
+        if self.is_nph() {
+            let head = tokio::select! {
+                biased;
+                head = within(
+                    header_deadline,
+                    parse_nph_response(&mut process_reader, &self.parse_config),
+                ) => head,
+                err = body_error.failed() => {
+                    let err = CgiServerError::request_body(err.into());
+                    return Outcome::Response(self.abort_response(err, info));
+                }
+            };
+            return Outcome::Response(match head {
+                None => self.abort_response(CgiServerError::Timeout, info),
+                Some(Ok(head)) => {
+                    let mut response = Response::new(self.remaining_body(
+                        process_reader,
+                        info.clone(),
+                        guard,
+                        deadline,
+                    ));
+                    *response.status_mut() = head.status;
+                    *response.headers_mut() = head.headers;
+                    if let Some(reason) = head.reason {
+                        response.extensions_mut().insert(reason);
+                    }
+                    response.extensions_mut().insert(info);
+                    response
+                }
+                Some(Err(err)) => self.header_error_response(err, &mut info).await,
+            });
+        }
+
+        let head = tokio::select! {
+            biased;
+            head = within(
+                header_deadline,
+                parse_cgi_response(&mut process_reader, &self.parse_config),
+            ) => head,
+            err = body_error.failed() => {
+                let err = CgiServerError::request_body(err.into());
+                return Outcome::Response(self.abort_response(err, info));
+            }
+        };
+        let head = match head {
+            None => return Outcome::Response(self.abort_response(CgiServerError::Timeout, info)),
+            Some(Ok(head)) => head,
+            Some(Err(err)) => {
+                return Outcome::Response(self.header_error_response(err, &mut info).await)
+            }
+        };
+        trace!("HEADERS: {:?}", head);
+
+        // A local redirect response is a local Location alone, without body
+        // (RFC 3875 §6.2.2). Otherwise, it is sent to the client as a 302
+        if let (Some(_), Some(location)) = (&self.local_redirect, head.location()) {
+            if head.status.is_none()
+                && is_local_location(location)
+                && head.headers.len() == 1
+                && matches!(
+                    within(header_deadline, process_reader.fill_buf()).await,
+                    Some(Ok(buf)) if buf.is_empty()
+                )
+            {
+                let count = redirect_info.map_or(0, |info| info.count) + 1;
+                if count > MAX_LOCAL_REDIRECTS {
+                    return Outcome::Response(
+                        self.error_response(CgiServerError::TooManyRedirects),
+                    );
+                }
+                let info = RedirectInfo {
+                    uri: req_uri,
+                    status: StatusCode::OK,
+                    count,
+                };
+                let redirect_req = match rewrite_request(location, &req_headers, info) {
+                    Ok(redirect_req) => redirect_req,
+                    Err(msg) => {
+                        return Outcome::Response(
+                            self.error_response(CgiServerError::BadRedirect(msg)),
+                        )
+                    }
+                };
+                trace!("LOCAL REDIRECT: {}", location);
+                return Outcome::LocalRedirect(redirect_req);
+            }
+        }
+
+        let status_code = match head_status(&head) {
+            Ok(status_code) => status_code,
+            Err(err) => {
+                let mut response = self.error_response(err);
+                response.extensions_mut().insert(info);
+                return Outcome::Response(response);
+            }
+        };
+
+        let mut response =
+            Response::new(self.remaining_body(process_reader, info.clone(), guard, deadline));
+        *response.status_mut() = status_code;
+        *response.headers_mut() = head.headers;
+        if let Some(reason) = head.reason {
+            response.extensions_mut().insert(reason);
+        }
+        response.extensions_mut().insert(info);
+        Outcome::Response(response)
+    }
+
+    /// Environment of the script: the meta-variables of a request
+    /// (RFC 3875 §4.1) and the variables inherited from the server
+    pub(crate) fn build_env<B>(
+        &self,
+        req: &Request<B>,
+        remote: SocketAddr,
+    ) -> HashMap<String, String> {
+        self.env_of(req, remote, true)
+    }
+
+    /// Meta-variables of a request sent to a backend, without the
+    /// variables of the server process such as PATH
+    pub(crate) fn meta_variables<B>(
+        &self,
+        req: &Request<B>,
+        remote: SocketAddr,
+    ) -> HashMap<String, String> {
+        self.env_of(req, remote, false)
+    }
+
+    fn env_of<B>(
+        &self,
+        req: &Request<B>,
+        remote: SocketAddr,
+        inherit: bool,
+    ) -> HashMap<String, String> {
+        let root_cow = self.root.to_string_lossy();
+        let root = if root_cow.is_empty() {
+            Cow::from("/")
         } else {
-            req_path
+            root_cow
+        };
+
+        let route = req.extensions().get::<RouteMatch>();
+        let req_path = req.uri().path();
+        let (script_name, path_info) = match route {
+            Some(route) => (route.script_name.as_str(), route.path_info.as_str()),
+            None if root != "/" && req_path.starts_with(root.deref()) => {
+                (root.deref(), &req_path[root.len()..])
+            }
+            None => (root.deref(), req_path),
         };
 
         let mut env: HashMap<String, String> = HashMap::new();
@@ -122,7 +550,7 @@ impl Script {
             env.insert("REQUEST_URI".to_string(), path_and_query.to_string());
         }
         env.insert("PATH_INFO".to_string(), path_info.to_string());
-        env.insert("SCRIPT_NAME".to_string(), root.to_string());
+        env.insert("SCRIPT_NAME".to_string(), script_name.to_string());
         env.insert(
             "SCRIPT_FILENAME".to_string(),
             self.path.to_string_lossy().to_string(),
@@ -132,17 +560,23 @@ impl Script {
         env.insert("REMOTE_HOST".to_string(), remote.ip().to_string());
         env.insert("REMOTE_PORT".to_string(), remote.port().to_string());
 
+        if let Some(info) = req.extensions().get::<RedirectInfo>() {
+            env.insert("REDIRECT_URL".to_string(), info.uri.path().to_string());
+            if let Some(query) = info.uri.query() {
+                env.insert("REDIRECT_QUERY_STRING".to_string(), query.to_string());
+            }
+            env.insert(
+                "REDIRECT_STATUS".to_string(),
+                info.status.as_u16().to_string(),
+            );
+        }
+
         for k in req.headers().keys() {
             let k = k.as_str().to_uppercase();
             if k == "PROXY" {
                 continue;
             }
-            let join_str: &str;
-            if k == "COOKIE" {
-                join_str = ";";
-            } else {
-                join_str = ",";
-            }
+            let join_str = if k == "COOKIE" { ";" } else { "," };
             let mut iter = req.headers().get_all(&k).into_iter();
             if let Some(Ok(first)) = iter.next().map(|e| e.to_str()) {
                 let vs = iter.fold(first.to_string(), |s, hv| {
@@ -175,227 +609,291 @@ impl Script {
             }
         }
 
-        if let Ok(env_path) = std::env::var("PATH") {
-            if !env_path.is_empty() {
-                env.insert("PATH".to_string(), env_path);
+        if inherit {
+            if let Ok(env_path) = std::env::var("PATH") {
+                if !env_path.is_empty() {
+                    env.insert("PATH".to_string(), env_path);
+                } else {
+                    env.insert(
+                        "PATH".to_string(),
+                        "/bin:/usr/bin:/usr/ucb:/usr/bsd:/usr/local/bin".to_string(),
+                    );
+                }
             } else {
                 env.insert(
                     "PATH".to_string(),
                     "/bin:/usr/bin:/usr/ucb:/usr/bsd:/usr/local/bin".to_string(),
                 );
             }
-        } else {
-            env.insert(
-                "PATH".to_string(),
-                "/bin:/usr/bin:/usr/ucb:/usr/bsd:/usr/local/bin".to_string(),
-            );
-        }
 
-        for e in &self.inherited_env {
-            if let Ok(k) = std::env::var(e) {
-                if !k.is_empty() {
-                    env.insert(e.clone(), k);
+            for e in &self.inherited_env {
+                if let Ok(k) = std::env::var(e) {
+                    if !k.is_empty() {
+                        env.insert(e.clone(), k);
+                    }
                 }
             }
-        }
 
-        for e in OS_SPECIFIC_VARS {
-            if let Ok(k) = std::env::var(e) {
-                if !k.is_empty() {
-                    env.insert(e.to_string(), k);
+            for e in OS_SPECIFIC_VARS {
+                if let Ok(k) = std::env::var(e) {
+                    if !k.is_empty() {
+                        env.insert(e.to_string(), k);
+                    }
                 }
             }
         }
 
-        for (k, v) in &self.env {
-            env.insert(k.clone(), v.clone());
+        if let Some(route) = route {
+            for (k, v) in &route.env {
+                env.insert(k.clone(), v.clone());
+            }
         }
 
-        let cwd_cow: Cow<str>;
-
-        if let Some(dir) = &self.dir {
-            cwd_cow = dir.to_string_lossy();
-        } else {
-            let p = Path::new(&self.path);
-            if let Some(parent) = p.parent() {
-                let parent_path = parent.to_string_lossy();
-                if parent_path.is_empty() {
-                    cwd_cow = Cow::from(".")
-                } else {
-                    cwd_cow = parent_path;
-                }
-            } else {
-                cwd_cow = Cow::from(".");
+        if let Some(interpreter) = self.interpreter() {
+            for (k, v) in &interpreter.env {
+                env.entry(k.clone()).or_insert_with(|| v.clone());
             }
         }
 
-        let cwd: &str = &cwd_cow;
-
-        let body = BodyStream::new(req.into_body())
-            .try_filter_map(|f| ready(Ok(f.into_data().ok())))
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
-
-        let child_opt = Command::new(&self.path)
-            .kill_on_drop(true)
-            .current_dir(cwd)
-            .args(&self.args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .envs(env)
-            .spawn();
-
-        if let Err(err) = child_opt {
-            return Ok(get_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!(
-                    "Cannot run cgi executable {} with error: {}",
-                    &self.path.to_string_lossy(),
-                    err
-                ),
-            ));
+        for (k, v) in &self.env {
+            env.insert(k.clone(), v.clone());
         }
 
-        let child = child_opt.unwrap();
-
-        let process_stream = process::new_process_typed(child, body, 1024)
-            //.foreach_err(|b| {
-            //    ready(Ok::<(), std::io::Error>(eprint!(
-            //        "{}",
-            //        String::from_utf8_lossy(&b)
-            //    )))
-            //});
-            .foreach_err(move |b| {
-                let mut error_writer = error_writer.clone();
-                Box::pin(async move { error_writer.write_all(&b).await })
-            });
-
-        let mut process_reader = StreamReader::new(process_stream);
-
-        let mut response_builder = Response::builder();
+        env
+    }
 
-        let mut has_header = false;
-        let mut status_code = None;
-        let mut has_location_header = false;
-        let mut has_content_type = false;
+    /// Standard input of the script. Request bodies without Content-Length
+    /// are spooled if enabled, and CONTENT_LENGTH is set to their size
+    pub(crate) async fn script_stdin(
+        &self,
+        req: Request<UnsyncBoxBody<Bytes, BoxError>>,
+        env: &mut HashMap<String, String>,
+    ) -> Result<ScriptStdin, CgiServerError> {
+        let is_chunked = req
+            .headers()
+            .get(TRANSFER_ENCODING)
+            .is_some_and(|encoding| encoding == "chunked");
+        if is_chunked && self.spool.is_none() {
+            return Err(CgiServerError::ChunkedNotSupported);
+        }
 
-        loop {
-            let mut line = String::new();
-            match process_reader.read_line(&mut line).await {
-                Ok(size) => {
-                    if size == 0 {
-                        break;
+        let must_spool = is_chunked
+            || (!req.headers().contains_key(CONTENT_LENGTH) && !req.body().is_end_stream());
+
+        let stdin = match self.spool.as_ref().filter(|_| must_spool) {
+            Some(config) => {
+                let spooled = match spool::spool(req.into_body(), config).await {
+                    Ok(spooled) => spooled,
+                    Err(err) => {
+                        let err = match err {
+                            SpoolError::TooLarge => CgiServerError::BodyTooLarge(config.max_size),
+                            SpoolError::Body(err) => CgiServerError::request_body(err),
+                            SpoolError::Io(err) => CgiServerError::Spool(err),
+                        };
+                        return Err(err);
                     }
-                    has_header = true;
-                    let line = line.trim();
-                    if line.len() == 0 {
-                        // end of headers
-                        break;
-                    }
-                    if let Some((k, v)) = line.split_once(":") {
-                        let (k, v) = (k.trim(), v.trim());
-                        trace!(format!("HEADER: key: {}, value: {}", k, v));
-                        if k == "Status" {
-                            let code_str: &str;
-                            if let Some((code, _)) = v.split_once(" ") {
-                                code_str = code;
-                            } else {
-                                code_str = v;
-                            }
-                            match code_str.parse::<u16>() {
-                                Ok(code) => {
-                                    match StatusCode::from_u16(code) {
-                                        Ok(code) => {
-                                            status_code = Some(code);
-                                        }
-                                        Err(err) => {
-                                            println!("Unknown code {} with error: {}", code, err);
-                                        }
-                                    };
-                                }
-                                Err(err) => {
-                                    println!("Cannot read status {} with error: {}", code_str, err);
-                                }
-                            }
-                        } else {
-                            let ktr = HeaderName::try_from(k);
-                            let vtr = HeaderValue::try_from(v);
-                            match (ktr, vtr) {
-                                (Ok(kt), Ok(vt)) => {
-                                    response_builder = response_builder.header(kt, vt);
-                                }
-                                (Ok(_), Err(err)) => {
-                                    println!("Cannot read header value: {}. Error: {}", v, err);
-                                }
-                                (Err(err), _) => {
-                                    println!("Cannot read header key: {}. Error: {}", k, err);
-                                }
-                            }
-                        }
-                        if k == "Location" && v != "" {
-                            has_location_header = true;
-                        }
-                        if k == "Content-Type" && v != "" {
-                            has_content_type = true;
-                        }
-                    } else {
-                        println!("Bad header line: {}", line)
-                    }
-                }
-                Err(err) => {
-                    return Ok(get_error_response(
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Cannot read header with error: {}", err),
-                    ))
+                };
+                env.insert("CONTENT_LENGTH".to_string(), spooled.len().to_string());
+                match spooled.into_stdin().await {
+                    Ok(stdin) => stdin,
+                    Err(err) => return Err(CgiServerError::Spool(err)),
                 }
             }
-        }
-
-        if !has_header {
-            return Ok(get_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("No header read"),
-            ));
-        }
+            None => ScriptStdin::Stream(
+                BodyStream::new(req.into_body())
+                    .try_filter_map(|f| ready(Ok(f.into_data().ok())))
+                    .map_err(std::io::Error::other)
+                    .boxed(),
+            ),
+        };
+        Ok(stdin)
+    }
 
-        if has_location_header && status_code.is_none() {
-            status_code = Some(StatusCode::FOUND);
+    /// Destination of the stderr of the script
+    pub(crate) fn script_stderr<W>(
+        &self,
+        request_id: String,
+        remote: SocketAddr,
+        error_writer: W,
+    ) -> ScriptStderr<W> {
+        match &self.stderr_sink {
+            Some(sink) => ScriptStderr::Sink(
+                sink.clone(),
+                StderrMeta {
+                    request_id,
+                    script: self.path.clone(),
+                    remote,
+                    pid: None,
+                },
+            ),
+            None => ScriptStderr::Writer(error_writer),
         }
+    }
 
-        if !has_content_type && status_code.is_none() {
-            return Ok(get_error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Missing required Content-Type header"),
-            ));
+    /// Response sent to the client for an error, rendered by the error
+    /// handler if any
+    fn error_response(&self, err: CgiServerError) -> Response<BoxBody<Bytes, std::io::Error>> {
+        if let Some(response) = self.error_handler.as_ref().and_then(|h| h.call(&err)) {
+            return response;
         }
+        get_error_response(err.status_code(), err.response_message())
+    }
 
-        match status_code {
-            Some(code) => {
-                response_builder = response_builder.status(code);
+    /// Response to a script whose header cannot be read. A script exiting
+    /// with a failure before sending its header gives a 502. The exit status
+    /// is waited for [`HEADER_EXIT_WAIT`] at most, the script may have closed
+    /// its stdout and keep running.
+    async fn header_error_response(
+        &self,
+        err: CgiParseError,
+        info: &mut ProcessInfo,
+    ) -> Response<BoxBody<Bytes, std::io::Error>> {
+        let err = match err {
+            CgiParseError::NoHeader | CgiParseError::Unterminated => {
+                match tokio::time::timeout(HEADER_EXIT_WAIT, info.wait()).await {
+                    Ok(Some(status)) if !status.success() => {
+                        CgiServerError::ExitedBeforeHeader(status)
+                    }
+                    _ if matches!(err, CgiParseError::NoHeader) => CgiServerError::NoHeader,
+                    _ => CgiServerError::Header(err),
+                }
             }
-            None => {
-                response_builder = response_builder.status(StatusCode::OK);
+            err => CgiServerError::Header(err),
+        };
+        let mut response = self.error_response(err);
+        response.extensions_mut().insert(info.clone());
+        response
+    }
+
+    /// Response of an error met before the script sent its header block.
+    /// The script is terminated as its guard has been dropped
+    fn abort_response(
+        &self,
+        err: CgiServerError,
+        info: ProcessInfo,
+    ) -> Response<BoxBody<Bytes, std::io::Error>> {
+        let mut response = self.error_response(err);
+        response.extensions_mut().insert(info);
+        response
+    }
+
+    /// Streams what remains of the script output as the response body.
+    ///
+    /// The stream fails with a [`CgiServerError`] if the script output
+    /// cannot be read, if the script exits with a failure or if the
+    /// deadline is reached. The script is killed if the body is dropped
+    /// before its end.
+    fn remaining_body<R>(
+        &self,
+        reader: R,
+        mut info: ProcessInfo,
+        guard: KillGuard,
+        deadline: Option<Instant>,
+    ) -> BoxBody<Bytes, std::io::Error>
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        let remaining_stream = ReaderStream::new(reader)
+            .map_ok(|bytes| {
+                trace!("remaining bytes: {}", String::from_utf8_lossy(&bytes));
+                Frame::data(bytes)
+            })
+            .map_err(CgiServerError::BodyIo);
+        let exit_stream = stream::once(async move {
+            let _guard = guard;
+            match info.wait().await {
+                Some(status) if !status.success() => {
+                    Some(Err(CgiServerError::ExitedAfterHeader(status)))
+                }
+                _ => None,
             }
+        })
+        .filter_map(ready);
+        let body = remaining_stream.chain(exit_stream);
+        match deadline {
+            Some(deadline) => self.output_body(until_deadline(body, deadline)),
+            None => self.output_body(body),
         }
+    }
 
-        let remaining_stream = ReaderStream::new(process_reader).map_ok(|bytes| {
-            trace!(format!(
-                "remaining bytes: {}",
-                String::from_utf8_lossy(&bytes)
-            ));
-            Frame::data(bytes)
+    /// Response body failing with an [`std::io::Error`] wrapping the
+    /// [`CgiServerError`] of the stream, after the error handler is called
+    pub(crate) fn output_body<S>(&self, stream: S) -> BoxBody<Bytes, std::io::Error>
+    where
+        S: Stream<Item = Result<Frame<Bytes>, CgiServerError>> + Send + Sync + 'static,
+    {
+        let error_handler = self.error_handler.clone();
+        let body_stream = stream.map_err(move |err| {
+            if let Some(handler) = &error_handler {
+                handler.call(&err);
+            }
+            std::io::Error::other(err)
         });
+        BoxBody::new(StreamBody::new(body_stream))
+    }
+}
+
+enum Outcome {
+    Response(Response<BoxBody<Bytes, std::io::Error>>),
+    /// Rewritten request of a local redirect response
+    LocalRedirect(Request<Empty<Bytes>>),
+}
+
+/// Value of the `X-Request-Id` header, or a number unique to the process
+pub(crate) fn request_id<B>(req: &Request<B>) -> String {
+    req.headers()
+        .get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .map_or_else(
+            || NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string(),
+            str::to_string,
+        )
+}
 
-        Ok(response_builder
-            .body(BoxBody::new(StreamBody::new(remaining_stream)))
-            .unwrap())
+/// Status of a response from its CGI header (RFC 3875 §6.3)
+pub(crate) fn head_status(head: &CgiResponseHead) -> Result<StatusCode, CgiServerError> {
+    match head.status {
+        Some(code) => Ok(code),
+        None if head.location().is_some() => Ok(StatusCode::FOUND),
+        None if !head.headers.contains_key(CONTENT_TYPE) => Err(CgiServerError::MissingContentType),
+        None => Ok(StatusCode::OK),
     }
 }
 
+/// Output of a future, or None if the deadline is reached first
+async fn within<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Stream ending with a [`CgiServerError::Timeout`] if the deadline is
+/// reached before its end. The stream is dropped at the deadline
+fn until_deadline<S>(
+    stream: S,
+    deadline: Instant,
+) -> impl Stream<Item = Result<Frame<Bytes>, CgiServerError>> + Send + Sync
+where
+    S: Stream<Item = Result<Frame<Bytes>, CgiServerError>> + Send + Sync + 'static,
+{
+    let stream = Some(Box::pin(stream));
+    stream::unfold(
+        (stream, Box::pin(sleep_until(deadline))),
+        |(mut stream, mut sleep)| async move {
+            let inner = stream.as_mut()?;
+            tokio::select! {
+                item = inner.next() => item.map(|item| (item, (stream, sleep))),
+                _ = sleep.as_mut() => Some((Err(CgiServerError::Timeout), (None, sleep))),
+            }
+        },
+    )
+}
+
 fn get_host_port(value: &str) -> Option<(&str, u16)> {
     let split: Vec<&str> = value.split(":").collect();
     if split.len() == 2 {
-        Some((split.get(0)?, split.get(1)?.parse().ok()?))
+        Some((split.first()?, split.get(1)?.parse().ok()?))
     } else {
         None
     }
@@ -0,0 +1,197 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Body, Request, Response};
+use tokio::io::AsyncWrite;
+
+use super::{BoxError, CgiServerError, RouteMatch, Script};
+
+/// Directory of scripts (Apache `ScriptAlias`).
+///
+/// The first path segment after the prefix picks the executable in the
+/// directory, the rest of the path is given as `PATH_INFO`.
+#[derive(Debug, Clone)]
+pub struct ScriptAlias {
+    /// URI prefix of the scripts, such as `/cgi-bin`
+    pub prefix: String,
+
+    /// Directory of the scripts
+    pub dir: PathBuf,
+
+    /// Settings of the scripts. Its path is replaced by the path of the
+    /// picked executable, and its root is ignored
+    pub script: Script,
+}
+
+/// Script picked for a request path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Resolved {
+    pub(crate) path: PathBuf,
+    pub(crate) route: RouteMatch,
+}
+
+impl ScriptAlias {
+    /// Picks the executable of a request path.
+    ///
+    /// The path of the executable is canonicalized and must stay inside the
+    /// directory. Files without an interpreter must be executable.
+    pub(crate) async fn resolve(&self, req_path: &str) -> Result<Resolved, CgiServerError> {
+        let not_found = || CgiServerError::NotFound(req_path.to_string());
+        let forbidden = || CgiServerError::Forbidden(req_path.to_string());
+
+        let prefix = self.prefix.trim_end_matches('/');
+        let rest = req_path.strip_prefix(prefix).ok_or_else(not_found)?;
+        let rest = rest.strip_prefix('/').ok_or_else(not_found)?;
+        let (name, path_info) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(not_found());
+        }
+
+        let dir = tokio::fs::canonicalize(&self.dir)
+            .await
+            .map_err(|_| not_found())?;
+        let path = tokio::fs::canonicalize(dir.join(name))
+            .await
+            .map_err(|_| not_found())?;
+        if !path.starts_with(&dir) {
+            return Err(forbidden());
+        }
+        let metadata = tokio::fs::metadata(&path).await.map_err(|_| not_found())?;
+        if !self.script.can_run(&path, &metadata) {
+            return Err(forbidden());
+        }
+
+        Ok(Resolved {
+            path,
+            route: RouteMatch {
+                script_name: format!("{}/{}", prefix, name),
+                path_info: path_info.to_string(),
+                env: Vec::new(),
+            },
+        })
+    }
+
+    pub async fn serve<B, W>(
+        &self,
+        mut req: Request<B>,
+        remote: SocketAddr,
+        error_writer: W,
+    ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>
+    where
+        B: Body<Data = Bytes> + Send + Unpin + 'static,
+        <B as Body>::Error: Into<BoxError> + Sync + Send,
+        W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
+    {
+        match self.resolve(req.uri().path()).await {
+            Ok(resolved) => {
+                let script = Script {
+                    path: resolved.path,
+                    ..self.script.clone()
+                };
+                req.extensions_mut().insert(resolved.route);
+                script.serve(req, remote, error_writer).await
+            }
+            Err(err) => Ok(self.script.error_response(err)),
+        }
+    }
+}
+
+impl Script {
+    /// Whether a file can be run as this script: it must have an
+    /// interpreter or be executable.
+    pub(crate) fn can_run(&self, path: &Path, metadata: &std::fs::Metadata) -> bool {
+        let interpreted = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| self.interpreters.contains_key(extension));
+        metadata.is_file() && (interpreted || is_executable(metadata))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn alias(dir: &std::path::Path) -> ScriptAlias {
+        ScriptAlias {
+            prefix: "/cgi-bin/".to_string(),
+            dir: dir.to_path_buf(),
+            script: Script {
+                path: PathBuf::new(),
+                root: PathBuf::new(),
+                dir: None,
+                env: Vec::new(),
+                args: Vec::new(),
+                inherited_env: Vec::new(),
+                spool: None,
+                nph: Default::default(),
+                local_redirect: None,
+                parse_config: Default::default(),
+                error_handler: None,
+                stderr_sink: None,
+                interpreters: Default::default(),
+                rlimits: None,
+                run_as: None,
+                sandbox: None,
+                kill_grace: None,
+                header_timeout: None,
+                request_timeout: None,
+            },
+        }
+    }
+
+    fn create(path: &std::path::Path, mode: u32) {
+        std::fs::write(path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cgi-bin");
+        std::fs::create_dir(&dir).unwrap();
+        create(&dir.join("test.cgi"), 0o755);
+        create(&dir.join("data.txt"), 0o644);
+        create(&tmp.path().join("secret.cgi"), 0o755);
+        std::os::unix::fs::symlink(tmp.path().join("secret.cgi"), dir.join("link.cgi")).unwrap();
+        let alias = alias(&dir);
+
+        let resolved = alias.resolve("/cgi-bin/test.cgi/a/b").await.unwrap();
+        assert_eq!(resolved.path, dir.canonicalize().unwrap().join("test.cgi"));
+        assert_eq!(resolved.route.script_name, "/cgi-bin/test.cgi");
+        assert_eq!(resolved.route.path_info, "/a/b");
+
+        for (path, status) in [
+            ("/cgi-bin/data.txt", 403),
+            ("/cgi-bin/link.cgi", 403),
+            ("/cgi-bin/../secret.cgi", 404),
+            ("/cgi-bin/missing.cgi", 404),
+            ("/cgi-bin/", 404),
+            ("/cgi-binary/test.cgi", 404),
+        ] {
+            let err = alias.resolve(path).await.unwrap_err();
+            assert_eq!(err.status_code().as_u16(), status, "{}", path);
+        }
+    }
+}
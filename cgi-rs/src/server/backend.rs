@@ -0,0 +1,111 @@
+use std::{fmt::Display, str::FromStr};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use futures::{stream, TryStreamExt};
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Frame, Response};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use super::{head_status, parse_cgi_response, CgiParseError, CgiServerError, Script};
+
+/// Address of a long-running application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendAddress {
+    /// Host and port
+    Tcp(String),
+    /// Path to a Unix socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Reads `unix:PATH` as a Unix socket, anything else as host and port
+impl FromStr for BackendAddress {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(BackendAddress::Unix(PathBuf::from(path)));
+        }
+        Ok(BackendAddress::Tcp(s.to_string()))
+    }
+}
+
+impl Display for BackendAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendAddress::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            BackendAddress::Unix(path) => write!(f, "unix:{}", path.to_string_lossy()),
+        }
+    }
+}
+
+pub(crate) type BackendReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+pub(crate) type BackendWriter = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+impl BackendAddress {
+    pub(crate) async fn connect(&self) -> Result<(BackendReader, BackendWriter), CgiServerError> {
+        let connect_error = |source| CgiServerError::Connect {
+            address: self.to_string(),
+            source,
+        };
+        match self {
+            BackendAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address).await.map_err(connect_error)?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            #[cfg(unix)]
+            BackendAddress::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path)
+                    .await
+                    .map_err(connect_error)?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+/// Sender of the CGI output of a backend, received by
+/// [`Script::backend_response`]
+pub(crate) type OutputSender = mpsc::Sender<std::io::Result<Bytes>>;
+pub(crate) type OutputReceiver = mpsc::Receiver<std::io::Result<Bytes>>;
+
+impl Script {
+    /// Response of a backend from its CGI output
+    pub(crate) async fn backend_response(
+        &self,
+        mut output: OutputReceiver,
+    ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, CgiServerError> {
+        let stream = stream::poll_fn(move |cx| output.poll_recv(cx));
+        let mut reader = BufReader::new(StreamReader::new(stream));
+        let head = parse_cgi_response(&mut reader, &self.parse_config)
+            .await
+            .map_err(|err| match err {
+                CgiParseError::NoHeader => CgiServerError::NoHeader,
+                err => CgiServerError::Header(err),
+            })?;
+        let status = head_status(&head)?;
+
+        let body = ReaderStream::new(reader)
+            .map_ok(Frame::data)
+            .map_err(CgiServerError::BodyIo);
+        let mut response = Response::new(self.output_body(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = head.headers;
+        if let Some(reason) = head.reason {
+            response.extensions_mut().insert(reason);
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    fmt::{Debug, Display},
+    path::PathBuf,
+    process::ExitStatus,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{Response, StatusCode};
+
+use super::{limits::exceeded_limit, BoxError, CgiParseError};
+
+/// Error met while serving a request with a script.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CgiServerError {
+    /// Chunked request received while spooling is disabled
+    ChunkedNotSupported,
+    /// Request body larger than the max size of the spool
+    BodyTooLarge(u64),
+    /// Request body of unknown length sent to a backend which needs it,
+    /// while spooling is disabled
+    LengthRequired,
+    /// Request body cannot be read
+    RequestBody(BoxError),
+    /// Request body was not received in time
+    RequestTimeout(BoxError),
+    /// Request body cannot be spooled
+    Spool(std::io::Error),
+    /// Script cannot be spawned
+    Spawn {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Script exited with a failure before sending its header
+    ExitedBeforeHeader(ExitStatus),
+    /// Script exited without sending any header
+    NoHeader,
+    /// Header of the script cannot be parsed
+    Header(CgiParseError),
+    /// Header of the script has neither Status, Location nor Content-Type
+    MissingContentType,
+    /// Local redirect response cannot be followed
+    BadRedirect(String),
+    /// More than [`super::MAX_LOCAL_REDIRECTS`] local redirects
+    TooManyRedirects,
+    /// Script output cannot be read after its header
+    BodyIo(std::io::Error),
+    /// Script exited with a failure after sending its header
+    ExitedAfterHeader(ExitStatus),
+    /// Script did not answer in time
+    Timeout,
+    /// No script for the request path
+    NotFound(String),
+    /// Script of the request path cannot be run
+    Forbidden(String),
+    /// Backend application cannot be reached
+    Connect {
+        address: String,
+        source: std::io::Error,
+    },
+    /// Exchange with the backend application failed
+    Backend(std::io::Error),
+    /// Script cannot be run as the configured user
+    RunAsRefused { path: PathBuf, reason: String },
+    /// Script cannot be inspected before being run as the configured user
+    RunAsCheck {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl CgiServerError {
+    /// Error of a request body which cannot be read, a timeout if an
+    /// [`std::io::Error`] of kind `TimedOut` caused it
+    pub(crate) fn request_body(err: BoxError) -> CgiServerError {
+        let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(err.as_ref());
+        while let Some(current) = cause {
+            let io_err = current.downcast_ref::<std::io::Error>();
+            if io_err.is_some_and(|io_err| io_err.kind() == std::io::ErrorKind::TimedOut) {
+                return CgiServerError::RequestTimeout(err);
+            }
+            // The source of an io::Error skips the error it wraps
+            cause = match io_err.and_then(std::io::Error::get_ref) {
+                Some(inner) => Some(inner),
+                None => current.source(),
+            };
+        }
+        CgiServerError::RequestBody(err)
+    }
+
+    /// Status of the default error response.
+    ///
+    /// Errors happening after the header has been sent abort the response
+    /// body instead, their status is only indicative.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            CgiServerError::ChunkedNotSupported | CgiServerError::RequestBody(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            CgiServerError::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            CgiServerError::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            CgiServerError::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            CgiServerError::Header(CgiParseError::Io(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            CgiServerError::ExitedBeforeHeader(_)
+            | CgiServerError::Header(_)
+            | CgiServerError::BodyIo(_)
+            | CgiServerError::ExitedAfterHeader(_) => StatusCode::BAD_GATEWAY,
+            CgiServerError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            CgiServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            CgiServerError::Forbidden(_) | CgiServerError::RunAsRefused { .. } => {
+                StatusCode::FORBIDDEN
+            }
+            CgiServerError::Connect { .. } | CgiServerError::Backend(_) => StatusCode::BAD_GATEWAY,
+            CgiServerError::Spool(_)
+            | CgiServerError::Spawn { .. }
+            | CgiServerError::RunAsCheck { .. }
+            | CgiServerError::NoHeader
+            | CgiServerError::MissingContentType
+            | CgiServerError::BadRedirect(_)
+            | CgiServerError::TooManyRedirects => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Body of the default error response. The checks of run_as are only
+    /// logged, their reasons describe the files of the server.
+    pub(crate) fn response_message(&self) -> String {
+        match self {
+            CgiServerError::RunAsRefused { .. } | CgiServerError::RunAsCheck { .. } => self
+                .status_code()
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+            err => err.to_string(),
+        }
+    }
+}
+
+impl Display for CgiServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgiServerError::ChunkedNotSupported => {
+                write!(f, "Chunked encoding is not supported by CGI.")
+            }
+            CgiServerError::BodyTooLarge(max_size) => {
+                write!(f, "Request body is larger than {} bytes", max_size)
+            }
+            CgiServerError::LengthRequired => {
+                write!(f, "Request body without Content-Length cannot be forwarded")
+            }
+            CgiServerError::RequestBody(err) => {
+                write!(f, "Cannot read request body with error: {}", err)
+            }
+            CgiServerError::RequestTimeout(err) => {
+                write!(f, "Request body was not received in time: {}", err)
+            }
+            CgiServerError::Spool(err) => {
+                write!(f, "Cannot spool request body with error: {}", err)
+            }
+            CgiServerError::Spawn { path, source } => write!(
+                f,
+                "Cannot run cgi executable {} with error: {}",
+                path.to_string_lossy(),
+                source
+            ),
+            CgiServerError::ExitedBeforeHeader(status) => {
+                write!(f, "Script exited with {} before sending header", status)?;
+                write_exceeded_limit(f, status)
+            }
+            CgiServerError::NoHeader => write!(f, "No header read"),
+            CgiServerError::Header(CgiParseError::Io(err)) => {
+                write!(f, "Cannot read header with error: {}", err)
+            }
+            CgiServerError::Header(err) => write!(f, "Bad header with error: {}", err),
+            CgiServerError::MissingContentType => {
+                write!(f, "Missing required Content-Type header")
+            }
+            CgiServerError::BadRedirect(msg) => write!(f, "{}", msg),
+            CgiServerError::TooManyRedirects => {
+                write!(
+                    f,
+                    "More than {} local redirects",
+                    super::MAX_LOCAL_REDIRECTS
+                )
+            }
+            CgiServerError::BodyIo(err) => {
+                write!(f, "Cannot read script output with error: {}", err)
+            }
+            CgiServerError::ExitedAfterHeader(status) => {
+                write!(f, "Script exited with {}", status)?;
+                write_exceeded_limit(f, status)
+            }
+            CgiServerError::Timeout => write!(f, "Script timed out"),
+            CgiServerError::NotFound(path) => write!(f, "No script for {}", path),
+            CgiServerError::Forbidden(path) => write!(f, "Cannot run script of {}", path),
+            CgiServerError::Connect { address, source } => {
+                write!(f, "Cannot connect to {} with error: {}", address, source)
+            }
+            CgiServerError::Backend(err) => write!(f, "Backend failed with error: {}", err),
+            CgiServerError::RunAsRefused { path, reason } => {
+                write!(f, "Cannot run {}: {}", path.to_string_lossy(), reason)
+            }
+            CgiServerError::RunAsCheck { path, source } => write!(
+                f,
+                "Cannot check {} with error: {}",
+                path.to_string_lossy(),
+                source
+            ),
+        }
+    }
+}
+
+fn write_exceeded_limit(f: &mut std::fmt::Formatter<'_>, status: &ExitStatus) -> std::fmt::Result {
+    match exceeded_limit(status) {
+        Some(limit) => write!(f, " ({} exceeded)", limit),
+        None => Ok(()),
+    }
+}
+
+impl std::error::Error for CgiServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CgiServerError::RequestBody(err) | CgiServerError::RequestTimeout(err) => {
+                Some(err.as_ref())
+            }
+            CgiServerError::Spool(err)
+            | CgiServerError::Spawn { source: err, .. }
+            | CgiServerError::Connect { source: err, .. }
+            | CgiServerError::RunAsCheck { source: err, .. }
+            | CgiServerError::Backend(err)
+            | CgiServerError::BodyIo(err) => Some(err),
+            CgiServerError::Header(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+type ErrorResponse = Response<BoxBody<Bytes, std::io::Error>>;
+type ErrorFn = dyn Fn(&CgiServerError) -> Option<ErrorResponse> + Send + Sync;
+
+/// Hook called with every error met by a script.
+///
+/// It may return the response sent to the client, if None the default error
+/// response is sent. For errors happening after the header has been sent
+/// the returned response is ignored, the body is aborted with an
+/// [`std::io::Error`] wrapping the [`CgiServerError`].
+#[derive(Clone)]
+pub struct ErrorHandler(Arc<ErrorFn>);
+
+impl ErrorHandler {
+    pub fn new<F>(f: F) -> ErrorHandler
+    where
+        F: Fn(&CgiServerError) -> Option<ErrorResponse> + Send + Sync + 'static,
+    {
+        ErrorHandler(Arc::new(f))
+    }
+
+    pub fn call(&self, err: &CgiServerError) -> Option<ErrorResponse> {
+        (self.0)(err)
+    }
+}
+
+impl Debug for ErrorHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
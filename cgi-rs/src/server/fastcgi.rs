@@ -0,0 +1,276 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use futures::{FutureExt, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{body::Body, Request, Response};
+use log::debug;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+};
+
+use crate::fastcgi::{
+    begin_request, encode_pairs, parse_end_request, read_record, write_record, write_stream,
+    BEGIN_REQUEST, END_REQUEST, KEEP_CONN, PARAMS, REQUEST_COMPLETE, RESPONDER, STDERR, STDIN,
+    STDOUT,
+};
+
+use super::{
+    backend::{BackendReader, BackendWriter, OutputSender},
+    process::spawn_stderr_forwarder,
+    request_id, BackendAddress, BoxError, CgiServerError, Script,
+};
+
+/// Id of the request sent on a connection, requests are not multiplexed
+const REQUEST_ID: u16 = 1;
+
+/// Number of idle connections kept by [`FastCgiPool::default`]
+const DEFAULT_MAX_IDLE: usize = 16;
+
+/// Long-running application speaking the FastCGI responder protocol.
+///
+/// The request gets the meta-variables of a CGI script, without the
+/// variables of the server process, and the path of the script is given as
+/// `SCRIPT_FILENAME`. Connections are kept open with `FCGI_KEEP_CONN` and
+/// reused from the pool.
+#[derive(Debug, Clone)]
+pub struct FastCgiBackend {
+    /// Address of the application
+    pub address: BackendAddress,
+
+    /// Settings of the requests. The script is not run, its path and
+    /// interpreters only set meta-variables
+    pub script: Script,
+
+    /// Idle connections to the application, shared by the clones of the
+    /// backend
+    pub pool: FastCgiPool,
+}
+
+/// Idle connections to a FastCGI application.
+///
+/// A connection goes back to the pool once the application has ended its
+/// request and the request body has been fully written. Connections whose
+/// response is dropped before the end are closed.
+#[derive(Clone)]
+pub struct FastCgiPool {
+    max_idle: usize,
+    idle: Arc<Mutex<Vec<(BackendReader, BackendWriter)>>>,
+}
+
+impl FastCgiPool {
+    /// Pool keeping at most `max_idle` connections. With 0, connections
+    /// are closed by the application after each request
+    pub fn new(max_idle: usize) -> Self {
+        FastCgiPool {
+            max_idle,
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Number of idle connections
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn take(&self) -> Option<(BackendReader, BackendWriter)> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    fn put(&self, reader: BackendReader, writer: BackendWriter) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push((reader, writer));
+        }
+    }
+}
+
+impl Default for FastCgiPool {
+    fn default() -> Self {
+        FastCgiPool::new(DEFAULT_MAX_IDLE)
+    }
+}
+
+impl std::fmt::Debug for FastCgiPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FastCgiPool")
+            .field("max_idle", &self.max_idle)
+            .field("idle", &self.idle())
+            .finish()
+    }
+}
+
+impl FastCgiBackend {
+    pub async fn serve<B, W>(
+        &self,
+        req: Request<B>,
+        remote: SocketAddr,
+        error_writer: W,
+    ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>
+    where
+        B: Body<Data = Bytes> + Send + Unpin + 'static,
+        <B as Body>::Error: Into<BoxError> + Sync + Send,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let req = req.map(|body| body.map_err(Into::into).boxed_unsync());
+        let request_id = request_id(&req);
+        let mut env = self.script.meta_variables(&req, remote);
+        let result = async {
+            let stdin = self.script.script_stdin(req, &mut env).await?;
+            let params = encode_pairs(env.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes())));
+            let (reader, writer) = self.begin(&params).await?;
+
+            // The connection is kept open until the end of the response, and
+            // given back if the request body has been fully written
+            let (writer_tx, writer_rx) = oneshot::channel();
+            let mut stdin = stdin.into_stream();
+            tokio::spawn(async move {
+                let mut writer = writer;
+                let written = async {
+                    while let Some(chunk) = stdin.next().await {
+                        write_stream(&mut writer, STDIN, REQUEST_ID, &chunk?).await?;
+                    }
+                    write_record(&mut writer, STDIN, REQUEST_ID, &[]).await?;
+                    writer.flush().await
+                }
+                .await;
+                match written {
+                    Ok(()) => {
+                        let _ = writer_tx.send(writer);
+                    }
+                    Err(err) => debug!("Cannot write request body to FastCGI backend: {}", err),
+                }
+            });
+
+            let (stderr_writer, stderr_reader) = tokio::io::duplex(8 * 1024);
+            spawn_stderr_forwarder(
+                stderr_reader,
+                self.script.script_stderr(request_id, remote, error_writer),
+                None,
+            );
+
+            let (output_tx, output_rx) = mpsc::channel(16);
+            let pool = self.pool.clone();
+            tokio::spawn(async move {
+                if let Some(reader) = read_records(reader, output_tx, stderr_writer).await {
+                    if let Ok(writer) = writer_rx.await {
+                        pool.put(reader, writer);
+                    }
+                }
+            });
+            self.script.backend_response(output_rx).await
+        }
+        .await;
+        Ok(match result {
+            Ok(response) => response,
+            Err(err) => self.script.error_response(err),
+        })
+    }
+
+    /// Sends the beginning of a request and its params, on an idle
+    /// connection if the application has not closed it, otherwise on a new
+    /// one
+    async fn begin(&self, params: &[u8]) -> Result<(BackendReader, BackendWriter), CgiServerError> {
+        let flags = if self.pool.max_idle > 0 { KEEP_CONN } else { 0 };
+        while let Some((mut reader, mut writer)) = self.pool.take() {
+            // An idle connection is readable once the application closed it
+            if reader.read(&mut [0; 1]).now_or_never().is_some() {
+                continue;
+            }
+            match write_begin(&mut writer, flags, params).await {
+                Ok(()) => return Ok((reader, writer)),
+                Err(err) => debug!("Idle FastCGI connection closed: {}", err),
+            }
+        }
+        let (reader, mut writer) = self.address.connect().await?;
+        write_begin(&mut writer, flags, params)
+            .await
+            .map_err(CgiServerError::Backend)?;
+        Ok((reader, writer))
+    }
+}
+
+async fn write_begin(writer: &mut BackendWriter, flags: u8, params: &[u8]) -> std::io::Result<()> {
+    write_record(
+        writer,
+        BEGIN_REQUEST,
+        REQUEST_ID,
+        &begin_request(RESPONDER, flags),
+    )
+    .await?;
+    write_stream(writer, PARAMS, REQUEST_ID, params).await?;
+    write_record(writer, PARAMS, REQUEST_ID, &[]).await
+}
+
+/// Reads the records of the application until the end of the request,
+/// sending stdout to the response and stderr to the stderr forwarder. The
+/// reader is given back if the request has been completed
+async fn read_records<E>(
+    mut reader: BackendReader,
+    output: OutputSender,
+    mut stderr: E,
+) -> Option<BackendReader>
+where
+    E: AsyncWrite + Unpin,
+{
+    loop {
+        let record = match read_record(&mut reader).await {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                let _ = output
+                    .send(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "FastCGI connection closed before the end of the request",
+                    )))
+                    .await;
+                return None;
+            }
+            Err(err) => {
+                let _ = output.send(Err(err)).await;
+                return None;
+            }
+        };
+        if record.request_id != REQUEST_ID {
+            continue;
+        }
+        match record.kind {
+            // The end of stdout is an empty record
+            STDOUT if record.content.is_empty() => {}
+            STDOUT => {
+                let sent = output.send(Ok(record.content)).await;
+                if sent.is_err() {
+                    // The response has been dropped
+                    return None;
+                }
+            }
+            STDERR => {
+                if let Err(err) = stderr.write_all(&record.content).await {
+                    debug!("Cannot forward FastCGI stderr: {}", err);
+                }
+            }
+            END_REQUEST => {
+                match parse_end_request(&record.content) {
+                    Ok((_, REQUEST_COMPLETE)) => return Some(reader),
+                    Ok((_, protocol_status)) => {
+                        let _ = output
+                            .send(Err(std::io::Error::other(format!(
+                                "FastCGI request rejected with protocol status {}",
+                                protocol_status
+                            ))))
+                            .await;
+                    }
+                    Err(err) => {
+                        let _ = output.send(Err(err)).await;
+                    }
+                }
+                return None;
+            }
+            _ => {}
+        }
+    }
+}
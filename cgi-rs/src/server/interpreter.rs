@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+/// Interpreter running the scripts of a file extension.
+///
+/// The script is given as argument to the interpreter, after its own
+/// arguments, so it needs neither the executable bit nor a shebang.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interpreter {
+    /// Path to the interpreter, looked up in PATH if relative
+    pub path: PathBuf,
+
+    /// Arguments given before the script
+    pub args: Vec<String>,
+
+    /// Environment variables needed by the interpreter.
+    /// They are not set if the request already defines them
+    pub env: Vec<(String, String)>,
+}
+
+impl Interpreter {
+    /// Interpreter without arguments. `php-cgi` gets `REDIRECT_STATUS`, which
+    /// it requires unless `cgi.force_redirect` is disabled
+    pub fn new(path: impl Into<PathBuf>) -> Interpreter {
+        let path = path.into();
+        let env = if is_php_cgi(&path) {
+            vec![("REDIRECT_STATUS".to_string(), "200".to_string())]
+        } else {
+            Vec::new()
+        };
+        Interpreter {
+            path,
+            args: Vec::new(),
+            env,
+        }
+    }
+
+    pub fn php_cgi() -> Interpreter {
+        Interpreter::new("php-cgi")
+    }
+
+    pub fn python() -> Interpreter {
+        Interpreter::new("python3")
+    }
+
+    pub fn perl() -> Interpreter {
+        Interpreter::new("perl")
+    }
+}
+
+fn is_php_cgi(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("php-cgi"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn php_cgi_env() {
+        assert_eq!(
+            Interpreter::new("/usr/bin/php-cgi8.2").env,
+            vec![("REDIRECT_STATUS".to_string(), "200".to_string())]
+        );
+        assert!(Interpreter::python().env.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{process::ExitStatus, str::FromStr};
+
+/// Resource limits and scheduling priority of a script process.
+///
+/// They are applied to the child before exec, unset ones are inherited from
+/// the server. Only supported on Unix, ignored elsewhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// CPU time in seconds (`RLIMIT_CPU`). The script gets SIGXCPU when it
+    /// is reached, and SIGKILL one second later
+    pub cpu_seconds: Option<u64>,
+    /// Size of the address space in bytes (`RLIMIT_AS`)
+    pub address_space: Option<u64>,
+    /// Number of open files (`RLIMIT_NOFILE`)
+    pub open_files: Option<u64>,
+    /// Number of processes of the user (`RLIMIT_NPROC`)
+    pub processes: Option<u64>,
+    /// Size of core dumps in bytes (`RLIMIT_CORE`)
+    pub core_size: Option<u64>,
+    /// Size of written files in bytes (`RLIMIT_FSIZE`)
+    pub file_size: Option<u64>,
+    /// Nice value, from -20 (highest priority) to 19
+    pub nice: Option<i32>,
+    /// I/O scheduling class and priority, only supported on Linux
+    pub io_priority: Option<IoPriority>,
+}
+
+/// I/O scheduling class of a process, as set by ionice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Realtime class, with a level from 0 (highest priority) to 7
+    Realtime(u8),
+    /// Best-effort class, with a level from 0 (highest priority) to 7
+    BestEffort(u8),
+    /// Only gets disk time when no other process needs it
+    Idle,
+}
+
+/// Reads `idle`, `best-effort:LEVEL` or `realtime:LEVEL`, the level
+/// defaulting to 4
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (
+                class,
+                level
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|level| *level <= 7)
+                    .ok_or_else(|| format!("Invalid I/O priority level {}", level))?,
+            ),
+            None => (s, 4),
+        };
+        match class {
+            "realtime" => Ok(IoPriority::Realtime(level)),
+            "best-effort" => Ok(IoPriority::BestEffort(level)),
+            "idle" => Ok(IoPriority::Idle),
+            _ => Err(format!("Unknown I/O scheduling class {}", class)),
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Applies the limits to the current process. Called between fork and
+    /// exec, so it only makes async-signal-safe calls.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self) -> std::io::Result<()> {
+        let limits = [
+            (
+                libc::RLIMIT_CPU,
+                self.cpu_seconds,
+                self.cpu_seconds.map(|s| s + 1),
+            ),
+            (libc::RLIMIT_AS, self.address_space, self.address_space),
+            (libc::RLIMIT_NOFILE, self.open_files, self.open_files),
+            (libc::RLIMIT_NPROC, self.processes, self.processes),
+            (libc::RLIMIT_CORE, self.core_size, self.core_size),
+            (libc::RLIMIT_FSIZE, self.file_size, self.file_size),
+        ];
+        for (resource, soft, hard) in limits {
+            if let (Some(soft), Some(hard)) = (soft, hard) {
+                let rlimit = libc::rlimit {
+                    rlim_cur: soft as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+                // SAFETY: rlimit is a valid pointer for the call
+                if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+
+        if let Some(nice) = self.nice {
+            // SAFETY: plain system call on the current process
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(io_priority) = self.io_priority {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+            let (class, level) = match io_priority {
+                IoPriority::Realtime(level) => (1, level),
+                IoPriority::BestEffort(level) => (2, level),
+                IoPriority::Idle => (3, 0),
+            };
+            let ioprio = (class << IOPRIO_CLASS_SHIFT) | level as libc::c_int;
+            // SAFETY: plain system call on the current process
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Limit whose signal killed a script, if any
+pub(crate) fn exceeded_limit(status: &ExitStatus) -> Option<&'static str> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        match status.signal() {
+            Some(libc::SIGXCPU) => Some("CPU time limit"),
+            Some(libc::SIGXFSZ) => Some("file size limit"),
+            _ => None,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_io_priority() {
+        assert_eq!("idle".parse(), Ok(IoPriority::Idle));
+        assert_eq!("best-effort".parse(), Ok(IoPriority::BestEffort(4)));
+        assert_eq!("realtime:0".parse(), Ok(IoPriority::Realtime(0)));
+        assert!("realtime:8".parse::<IoPriority>().is_err());
+        assert!("fast".parse::<IoPriority>().is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::{process::ExitStatus, process::Stdio, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    process::{Child, ChildStdout, Command},
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_util::io::ReaderStream;
+
+use super::{
+    limits::exceeded_limit,
+    stderr::{forward_lines, StderrMeta, StderrSink},
+};
+
+/// Standard input of a script.
+pub(crate) enum ScriptStdin {
+    /// Request body written to a pipe
+    Stream(BoxStream<'static, Result<Bytes, std::io::Error>>),
+    /// Seekable file holding the whole request body
+    File(std::fs::File),
+}
+
+/// Destination of the stderr of a script.
+pub(crate) enum ScriptStderr<W> {
+    /// Raw bytes copied to a writer
+    Writer(W),
+    /// Lines written to a sink, the pid is set once the script is spawned
+    Sink(Arc<dyn StderrSink>, StderrMeta),
+}
+
+/// Process of a running script, inserted in the extensions of its response.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pid: Option<u32>,
+    exit: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl ProcessInfo {
+    /// Pid of the script process
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Exit status, if the script has already exited
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit.borrow()
+    }
+
+    /// Waits for the script to exit and its stderr to be forwarded.
+    /// Returns None if the exit status cannot be collected
+    pub async fn wait(&mut self) -> Option<ExitStatus> {
+        match self.exit.wait_for(Option::is_some).await {
+            Ok(status) => *status,
+            Err(_) => None,
+        }
+    }
+}
+
+/// Time given to a cancelled script to exit after SIGTERM, before its
+/// process group gets SIGKILL
+pub const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Time given to the stderr of an exited script to be forwarded before its
+/// exit status is published, a child of the script may keep stderr open
+const STDERR_DRAIN: Duration = Duration::from_millis(100);
+
+/// Kills the script when dropped, unless it has already exited.
+#[derive(Debug)]
+pub(crate) struct KillGuard(#[allow(dead_code)] oneshot::Sender<()>);
+
+pub(crate) struct ScriptProcess {
+    pub(crate) stdout: ChildStdout,
+    pub(crate) info: ProcessInfo,
+    pub(crate) guard: KillGuard,
+    /// Error of the streamed request body, its stdin is closed
+    pub(crate) body_error: BodyError,
+}
+
+/// Receives the error met while reading a streamed request body.
+pub(crate) struct BodyError(Option<oneshot::Receiver<std::io::Error>>);
+
+impl BodyError {
+    /// Waits for the request body to fail, never resolves if it does not
+    pub(crate) async fn failed(&mut self) -> std::io::Error {
+        if let Some(receiver) = self.0.as_mut() {
+            if let Ok(err) = receiver.await {
+                return err;
+            }
+            self.0 = None;
+        }
+        std::future::pending().await
+    }
+}
+
+impl ScriptStdin {
+    /// Request body as a stream of bytes
+    pub(crate) fn into_stream(self) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+        match self {
+            ScriptStdin::Stream(stream) => stream,
+            ScriptStdin::File(file) => ReaderStream::new(tokio::fs::File::from_std(file)).boxed(),
+        }
+    }
+}
+
+/// Spawns the task forwarding the stderr of a script, ending with stderr
+pub(crate) fn spawn_stderr_forwarder<R, W>(
+    mut reader: R,
+    stderr: ScriptStderr<W>,
+    pid: Option<u32>,
+) -> JoinHandle<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    match stderr {
+        ScriptStderr::Writer(mut error_writer) => tokio::spawn(async move {
+            if let Err(err) = tokio::io::copy(&mut reader, &mut error_writer).await {
+                debug!("Cannot forward script stderr: {}", err);
+            }
+        }),
+        ScriptStderr::Sink(sink, mut meta) => {
+            meta.pid = pid;
+            tokio::spawn(async move {
+                forward_lines(reader, sink.as_ref(), &meta).await;
+            })
+        }
+    }
+}
+
+/// Spawns the script in its own process group, and the tasks feeding its
+/// stdin, forwarding its stderr and collecting its exit status.
+///
+/// When the guard is dropped before the script exits, the group gets
+/// SIGTERM, then SIGKILL once the script exits or after kill_grace. The
+/// script is always reaped.
+pub(crate) fn spawn_script<W>(
+    command: &mut Command,
+    stdin: ScriptStdin,
+    stderr: ScriptStderr<W>,
+    kill_grace: Duration,
+) -> std::io::Result<ScriptProcess>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let stdin = match stdin {
+        ScriptStdin::File(file) => {
+            command.stdin(Stdio::from(file));
+            None
+        }
+        ScriptStdin::Stream(stream) => {
+            command.stdin(Stdio::piped());
+            Some(stream)
+        }
+    };
+    #[cfg(unix)]
+    // SAFETY: setpgid is async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut body_error = BodyError(None);
+    if let (Some(mut body), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        let (error_tx, error_rx) = oneshot::channel();
+        body_error = BodyError(Some(error_rx));
+        tokio::spawn(async move {
+            while let Some(chunk) = body.next().await {
+                let bytes = match chunk {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        debug!("Cannot read request body: {}", err);
+                        let _ = error_tx.send(err);
+                        break;
+                    }
+                };
+                if let Err(err) = child_stdin.write_all(&bytes).await {
+                    debug!("Cannot write request body to script: {}", err);
+                    break;
+                }
+            }
+            // child_stdin is dropped here, closing the pipe
+        });
+    }
+
+    let pid = child.id();
+    let forwarder = child
+        .stderr
+        .take()
+        .map(|child_stderr| spawn_stderr_forwarder(child_stderr, stderr, pid));
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let (exit_tx, exit_rx) = watch::channel(None);
+    let (kill_tx, kill_rx) = oneshot::channel::<()>();
+
+    tokio::spawn(async move {
+        let mut cancelled = None;
+        let status = tokio::select! {
+            status = child.wait() => status,
+            _ = kill_rx => {
+                let deadline = Instant::now() + kill_grace;
+                cancelled = Some(deadline);
+                terminate(&mut child, pid, deadline).await
+            }
+        };
+        if let Some(forwarder) = forwarder {
+            let _ = tokio::time::timeout(STDERR_DRAIN, forwarder).await;
+        }
+        match status {
+            Ok(status) => {
+                match exceeded_limit(&status) {
+                    Some(limit) => warn!(
+                        "Script process {:?} exited with {}, {} exceeded",
+                        pid, status, limit
+                    ),
+                    None => debug!("Script process {:?} exited with {}", pid, status),
+                }
+                let _ = exit_tx.send(Some(status));
+            }
+            Err(err) => debug!("Cannot wait for script process {:?}: {}", pid, err),
+        }
+        if let (Some(deadline), Some(pgid)) = (cancelled, pid) {
+            kill_group(pgid, deadline).await;
+        }
+    });
+
+    Ok(ScriptProcess {
+        stdout,
+        info: ProcessInfo { pid, exit: exit_rx },
+        guard: KillGuard(kill_tx),
+        body_error,
+    })
+}
+
+/// Sends SIGTERM to the process group of a script, and SIGKILL if the
+/// script is still running at the deadline. The script is reaped.
+#[cfg(unix)]
+async fn terminate(
+    child: &mut Child,
+    pid: Option<u32>,
+    deadline: Instant,
+) -> std::io::Result<ExitStatus> {
+    let Some(pgid) = pid else {
+        // Already reaped
+        return child.wait().await;
+    };
+    debug!("Terminating script process group {}", pgid);
+    signal_group(pgid, libc::SIGTERM);
+    match tokio::time::timeout_at(deadline, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            debug!("Killing script process group {}", pgid);
+            signal_group(pgid, libc::SIGKILL);
+            child.wait().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate(
+    child: &mut Child,
+    pid: Option<u32>,
+    _deadline: Instant,
+) -> std::io::Result<ExitStatus> {
+    debug!("Killing script process {:?}", pid);
+    let _ = child.start_kill();
+    child.wait().await
+}
+
+/// Kills what remains of the process group of a terminated script at the
+/// deadline, or as soon as the group is empty
+#[cfg(unix)]
+async fn kill_group(pgid: u32, deadline: Instant) {
+    while signal_group(pgid, 0) == 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    if signal_group(pgid, libc::SIGKILL) == 0 {
+        debug!("Killing the rest of script process group {}", pgid);
+    }
+}
+
+#[cfg(not(unix))]
+async fn kill_group(_pgid: u32, _deadline: Instant) {}
+
+#[cfg(unix)]
+fn signal_group(pgid: u32, signal: libc::c_int) -> libc::c_int {
+    // SAFETY: plain system call. The group is the one of the script, its
+    // id cannot be reused while the script is not reaped or the group has
+    // members
+    unsafe { libc::killpg(pgid as libc::pid_t, signal) }
+}
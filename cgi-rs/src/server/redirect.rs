@@ -0,0 +1,96 @@
+use std::{fmt::Debug, future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, Empty};
+use hyper::{
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    Method, Request, Response, StatusCode, Uri,
+};
+
+/// Max number of local redirects followed for a single client request
+pub const MAX_LOCAL_REDIRECTS: usize = 10;
+
+/// Handler of the local redirect responses of a script (RFC 3875 §6.2.2).
+#[derive(Debug, Clone)]
+pub enum LocalRedirect {
+    /// The rewritten request is served by the same script, without the
+    /// route of the original request
+    SameScript,
+    /// The rewritten request is served by another service
+    Service(RedirectService),
+}
+
+pub type RedirectFuture =
+    Pin<Box<dyn Future<Output = Response<BoxBody<Bytes, std::io::Error>>> + Send>>;
+
+/// Service serving the rewritten request of a local redirect.
+#[derive(Clone)]
+pub struct RedirectService(
+    Arc<dyn Fn(Request<Empty<Bytes>>, SocketAddr) -> RedirectFuture + Send + Sync>,
+);
+
+impl RedirectService {
+    pub fn new<F>(f: F) -> RedirectService
+    where
+        F: Fn(Request<Empty<Bytes>>, SocketAddr) -> RedirectFuture + Send + Sync + 'static,
+    {
+        RedirectService(Arc::new(f))
+    }
+
+    pub fn call(&self, req: Request<Empty<Bytes>>, remote: SocketAddr) -> RedirectFuture {
+        (self.0)(req, remote)
+    }
+}
+
+impl Debug for RedirectService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RedirectService")
+    }
+}
+
+/// Original request of a local redirect.
+///
+/// It is stored in the extensions of the rewritten request and used to set
+/// the `REDIRECT_*` meta-variables.
+#[derive(Debug, Clone)]
+pub struct RedirectInfo {
+    /// URI of the original request
+    pub uri: Uri,
+    /// Status of the original response
+    pub status: StatusCode,
+    /// Number of local redirects already followed
+    pub count: usize,
+}
+
+/// Builds the `GET` request of a local redirect. The body of the original
+/// request is not forwarded.
+///
+/// The [`RouteMatch`](super::RouteMatch) of the original request is dropped,
+/// as it does not match the new path: the same script gets `SCRIPT_NAME`
+/// from its root and `PATH_INFO` from the new path, a redirect service routes
+/// the new path itself.
+pub(crate) fn rewrite_request(
+    location: &str,
+    headers: &HeaderMap,
+    info: RedirectInfo,
+) -> Result<Request<Empty<Bytes>>, String> {
+    let uri = Uri::try_from(location)
+        .map_err(|err| format!("Cannot read location {} with error: {}", location, err))?;
+    let mut req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .extension(info)
+        .body(Empty::new())
+        .map_err(|err| format!("Cannot build request to {} with error: {}", location, err))?;
+    for (k, v) in headers {
+        if k != CONTENT_LENGTH && k != CONTENT_TYPE && k != TRANSFER_ENCODING {
+            req.headers_mut().append(k, v.clone());
+        }
+    }
+    Ok(req)
+}
+
+/// A location is local if it is an absolute path without authority
+pub(crate) fn is_local_location(location: &str) -> bool {
+    location.starts_with('/') && !location.starts_with("//")
+}
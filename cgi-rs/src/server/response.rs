@@ -0,0 +1,412 @@
+use hyper::{
+    ext::ReasonPhrase,
+    header::{HeaderMap, HeaderName, HeaderValue, LOCATION},
+    StatusCode, Version,
+};
+use log::warn;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// How malformed lines of the script output are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Malformed lines are logged and ignored
+    #[default]
+    Lenient,
+    /// Malformed lines make the response fail
+    Strict,
+}
+
+/// Configuration of the parser of the script response headers.
+#[derive(Debug, Clone)]
+pub struct ParseConfig {
+    /// Handling of malformed lines
+    pub mode: ParseMode,
+
+    /// Max number of header lines
+    pub max_headers: usize,
+
+    /// Max length of a header line, end of line included
+    pub max_line_length: usize,
+
+    /// Max size of the whole header block
+    pub max_header_size: usize,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        ParseConfig {
+            mode: ParseMode::default(),
+            max_headers: 100,
+            max_line_length: 8 * 1024,
+            max_header_size: 64 * 1024,
+        }
+    }
+}
+
+/// Error of [`parse_cgi_response`].
+#[derive(Debug)]
+pub enum CgiParseError {
+    /// The script output is empty
+    NoHeader,
+    /// The script output cannot be read
+    Io(std::io::Error),
+    /// A header line is longer than `ParseConfig::max_line_length`
+    LineTooLong,
+    /// There are more than `ParseConfig::max_headers` header lines
+    TooManyHeaders,
+    /// The header block is larger than `ParseConfig::max_header_size`
+    HeadersTooLarge,
+    /// The output ends before the empty line closing the header block
+    Unterminated,
+    /// A header line is not `name: value`
+    BadLine(String),
+    /// The header name or value is not valid
+    BadHeader(String),
+    /// The status cannot be read
+    BadStatus(String),
+}
+
+impl std::fmt::Display for CgiParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgiParseError::NoHeader => write!(f, "no header read"),
+            CgiParseError::Io(err) => write!(f, "cannot read header: {}", err),
+            CgiParseError::LineTooLong => write!(f, "header line is too long"),
+            CgiParseError::TooManyHeaders => write!(f, "too many header lines"),
+            CgiParseError::HeadersTooLarge => write!(f, "header block is too large"),
+            CgiParseError::Unterminated => write!(f, "header block is not terminated"),
+            CgiParseError::BadLine(line) => write!(f, "bad header line: {}", line),
+            CgiParseError::BadHeader(line) => write!(f, "bad header: {}", line),
+            CgiParseError::BadStatus(status) => write!(f, "bad status: {}", status),
+        }
+    }
+}
+
+impl std::error::Error for CgiParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CgiParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Header block of a CGI response.
+#[derive(Debug, Default)]
+pub struct CgiResponseHead {
+    /// Value of the `Status` header
+    pub status: Option<StatusCode>,
+
+    /// Non-canonical reason phrase of the `Status` header
+    pub reason: Option<ReasonPhrase>,
+
+    /// All headers except `Status`, in order, repeated ones included
+    pub headers: HeaderMap,
+}
+
+impl CgiResponseHead {
+    /// Value of the `Location` header
+    pub fn location(&self) -> Option<&str> {
+        self.headers
+            .get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// Reads the header block of a CGI response (RFC 3875 §6.3).
+///
+/// The reader is left at the beginning of the body. Header names are case
+/// insensitive.
+pub async fn parse_cgi_response<R>(
+    reader: &mut R,
+    config: &ParseConfig,
+) -> Result<CgiResponseHead, CgiParseError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = CgiResponseHead::default();
+    let mut lines = HeaderLines::new(config);
+
+    let mut has_header = false;
+    while let Some(line) = lines.next(reader).await? {
+        has_header = true;
+        if line.is_empty() {
+            return Ok(head);
+        }
+        let (name, value) = match parse_header_line(&line, config.mode)? {
+            Some(header) => header,
+            None => continue,
+        };
+        if name == "status" {
+            match parse_status(value.as_bytes()) {
+                Some((status, reason)) => {
+                    head.status = Some(status);
+                    head.reason = reason;
+                }
+                None => {
+                    let status = String::from_utf8_lossy(value.as_bytes()).to_string();
+                    if config.mode == ParseMode::Strict {
+                        return Err(CgiParseError::BadStatus(status));
+                    }
+                    warn!("Ignoring bad status: {}", status);
+                }
+            }
+        } else {
+            head.headers.append(name, value);
+        }
+    }
+
+    if !has_header {
+        Err(CgiParseError::NoHeader)
+    } else if config.mode == ParseMode::Strict {
+        Err(CgiParseError::Unterminated)
+    } else {
+        Ok(head)
+    }
+}
+
+/// Status line and header block of an NPH response.
+pub(crate) struct NphResponseHead {
+    pub(crate) status: StatusCode,
+    pub(crate) reason: Option<ReasonPhrase>,
+    pub(crate) headers: HeaderMap,
+}
+
+/// Reads the status line and headers written by an NPH script.
+pub(crate) async fn parse_nph_response<R>(
+    reader: &mut R,
+    config: &ParseConfig,
+) -> Result<NphResponseHead, CgiParseError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = HeaderLines::new(config);
+
+    let status_line = lines.next(reader).await?.ok_or(CgiParseError::NoHeader)?;
+    let bad_status = || CgiParseError::BadStatus(String::from_utf8_lossy(&status_line).to_string());
+    let (version, status) = match status_line.iter().position(|b| *b == b' ') {
+        Some(i) => (&status_line[..i], &status_line[i + 1..]),
+        None => return Err(bad_status()),
+    };
+    if parse_version(version).is_none() {
+        return Err(bad_status());
+    }
+    let (status, reason) = parse_status(status).ok_or_else(bad_status)?;
+
+    let mut headers = HeaderMap::new();
+    while let Some(line) = lines.next(reader).await? {
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = parse_header_line(&line, config.mode)? {
+            headers.append(name, value);
+        }
+    }
+
+    Ok(NphResponseHead {
+        status,
+        reason,
+        headers,
+    })
+}
+
+/// Reads header lines while enforcing the limits of a [`ParseConfig`].
+struct HeaderLines<'a> {
+    config: &'a ParseConfig,
+    count: usize,
+    size: usize,
+}
+
+impl<'a> HeaderLines<'a> {
+    fn new(config: &'a ParseConfig) -> Self {
+        HeaderLines {
+            config,
+            count: 0,
+            size: 0,
+        }
+    }
+
+    /// Next line without its end of line, None at the end of the output
+    async fn next<R>(&mut self, reader: &mut R) -> Result<Option<Vec<u8>>, CgiParseError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut line = Vec::new();
+        loop {
+            let available = reader.fill_buf().await.map_err(CgiParseError::Io)?;
+            if available.is_empty() {
+                break;
+            }
+            let (used, done) = match available.iter().position(|b| *b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (available.len(), false),
+            };
+            if line.len() + used > self.config.max_line_length {
+                return Err(CgiParseError::LineTooLong);
+            }
+            line.extend_from_slice(&available[..used]);
+            reader.consume(used);
+            if done {
+                break;
+            }
+        }
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        self.size += line.len();
+        if self.size > self.config.max_header_size {
+            return Err(CgiParseError::HeadersTooLarge);
+        }
+
+        while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+            line.pop();
+        }
+        if !line.is_empty() {
+            self.count += 1;
+            if self.count > self.config.max_headers {
+                return Err(CgiParseError::TooManyHeaders);
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Parses a `name: value` line. In lenient mode, bad lines are logged and
+/// None is returned.
+fn parse_header_line(
+    line: &[u8],
+    mode: ParseMode,
+) -> Result<Option<(HeaderName, HeaderValue)>, CgiParseError> {
+    let header = match line.iter().position(|b| *b == b':') {
+        Some(i) => {
+            let name = HeaderName::from_bytes(line[..i].trim_ascii());
+            let value = HeaderValue::from_bytes(line[i + 1..].trim_ascii());
+            match (name, value) {
+                (Ok(name), Ok(value)) => Ok((name, value)),
+                _ => Err(CgiParseError::BadHeader(
+                    String::from_utf8_lossy(line).to_string(),
+                )),
+            }
+        }
+        None => Err(CgiParseError::BadLine(
+            String::from_utf8_lossy(line).to_string(),
+        )),
+    };
+    match header {
+        Ok(header) => Ok(Some(header)),
+        Err(err) if mode == ParseMode::Strict => Err(err),
+        Err(err) => {
+            warn!("Ignoring {}", err);
+            Ok(None)
+        }
+    }
+}
+
+/// Parses `code [reason]`
+fn parse_status(value: &[u8]) -> Option<(StatusCode, Option<ReasonPhrase>)> {
+    let value = value.trim_ascii();
+    let (code, reason) = match value.iter().position(|b| *b == b' ') {
+        Some(i) => (&value[..i], Some(value[i + 1..].trim_ascii())),
+        None => (value, None),
+    };
+    if code.len() != 3 {
+        return None;
+    }
+    let status = StatusCode::from_bytes(code).ok()?;
+    let reason = reason
+        .filter(|reason| !reason.is_empty())
+        .filter(|reason| status.canonical_reason().map(str::as_bytes) != Some(*reason))
+        .and_then(|reason| ReasonPhrase::try_from(reason).ok());
+    Some((status, reason))
+}
+
+fn parse_version(version: &[u8]) -> Option<Version> {
+    match version {
+        b"HTTP/1.0" => Some(Version::HTTP_10),
+        b"HTTP/1.1" => Some(Version::HTTP_11),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{CONTENT_TYPE, SET_COOKIE};
+
+    async fn parse(output: &str, config: &ParseConfig) -> Result<CgiResponseHead, CgiParseError> {
+        let mut reader = output.as_bytes();
+        parse_cgi_response(&mut reader, config).await
+    }
+
+    #[tokio::test]
+    async fn parse_case_insensitive() {
+        let head = parse(
+            "STATUS: 404 Not Found\r\ncontent-type: text/plain\r\nlocation: /x\r\n\r\nbody",
+            &ParseConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(head.status, Some(StatusCode::NOT_FOUND));
+        assert!(head.reason.is_none());
+        assert_eq!(head.headers[CONTENT_TYPE], "text/plain");
+        assert_eq!(head.location(), Some("/x"));
+    }
+
+    #[tokio::test]
+    async fn parse_repeated_headers() {
+        let head = parse(
+            "Content-Type: text/plain\nSet-Cookie: a=1\nSet-Cookie: b=2\n\n",
+            &ParseConfig::default(),
+        )
+        .await
+        .unwrap();
+        let cookies: Vec<_> = head.headers.get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+    }
+
+    #[tokio::test]
+    async fn parse_bad_line() {
+        let output = "Content-Type: text/plain\nbad line\n\n";
+        let head = parse(output, &ParseConfig::default()).await.unwrap();
+        assert_eq!(head.headers.len(), 1);
+
+        let strict = ParseConfig {
+            mode: ParseMode::Strict,
+            ..ParseConfig::default()
+        };
+        let res = parse(output, &strict).await;
+        assert!(matches!(res, Err(CgiParseError::BadLine(_))));
+    }
+
+    #[tokio::test]
+    async fn parse_limits() {
+        let config = ParseConfig {
+            max_line_length: 16,
+            ..ParseConfig::default()
+        };
+        let res = parse("Content-Type: text/plain\n\n", &config).await;
+        assert!(matches!(res, Err(CgiParseError::LineTooLong)));
+
+        let config = ParseConfig {
+            max_headers: 1,
+            ..ParseConfig::default()
+        };
+        let res = parse("A: 1\nB: 2\n\n", &config).await;
+        assert!(matches!(res, Err(CgiParseError::TooManyHeaders)));
+
+        let config = ParseConfig {
+            max_header_size: 8,
+            ..ParseConfig::default()
+        };
+        let res = parse("A: 1\nB: 2\n\n", &config).await;
+        assert!(matches!(res, Err(CgiParseError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn parse_empty() {
+        let res = parse("", &ParseConfig::default()).await;
+        assert!(matches!(res, Err(CgiParseError::NoHeader)));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Body, Request, Response, StatusCode};
+use regex::Regex;
+use tokio::io::AsyncWrite;
+
+use super::{get_error_response, BoxError, Script};
+
+/// Pattern matched against the path of a request.
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// Path equal to the prefix or starting with the prefix followed by `/`
+    Prefix(String),
+    /// Path matched by the regex from its start. Named capture groups are
+    /// exported to the script as environment variables
+    Regex(Regex),
+}
+
+/// Result of the routing of a request, inserted in its extensions.
+///
+/// It overrides the `SCRIPT_NAME` and `PATH_INFO` computed from
+/// [`Script::root`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMatch {
+    /// Part of the path matched by the route
+    pub script_name: String,
+    /// Remaining part of the path
+    pub path_info: String,
+    /// Environment variables set from the route
+    pub env: Vec<(String, String)>,
+}
+
+impl Route {
+    pub fn matches(&self, path: &str) -> Option<RouteMatch> {
+        match self {
+            Route::Prefix(prefix) => {
+                let prefix = prefix.trim_end_matches('/');
+                let path_info = path.strip_prefix(prefix)?;
+                if !path_info.is_empty() && !path_info.starts_with('/') {
+                    return None;
+                }
+                Some(RouteMatch {
+                    script_name: prefix.to_string(),
+                    path_info: path_info.to_string(),
+                    env: Vec::new(),
+                })
+            }
+            Route::Regex(regex) => {
+                let captures = regex.captures(path)?;
+                let matched = captures.get(0)?;
+                if matched.start() != 0 {
+                    return None;
+                }
+                let env = regex
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        captures
+                            .name(name)
+                            .map(|value| (name.to_string(), value.as_str().to_string()))
+                    })
+                    .collect();
+                Some(RouteMatch {
+                    script_name: matched.as_str().to_string(),
+                    path_info: path[matched.end()..].to_string(),
+                    env,
+                })
+            }
+        }
+    }
+}
+
+/// Serves each request with the script of the first matching route.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptRouter {
+    routes: Vec<(Route, Script)>,
+    fallback: Option<Script>,
+}
+
+impl ScriptRouter {
+    pub fn new() -> ScriptRouter {
+        ScriptRouter::default()
+    }
+
+    /// Routes the requests whose path is under prefix to script
+    pub fn prefix(self, prefix: impl Into<String>, script: Script) -> ScriptRouter {
+        self.route(Route::Prefix(prefix.into()), script)
+    }
+
+    /// Routes the requests whose path is matched by regex to script
+    pub fn regex(self, regex: Regex, script: Script) -> ScriptRouter {
+        self.route(Route::Regex(regex), script)
+    }
+
+    pub fn route(mut self, route: Route, script: Script) -> ScriptRouter {
+        self.routes.push((route, script));
+        self
+    }
+
+    /// Script serving the requests matched by no route.
+    /// If not set, these requests get a 404
+    pub fn fallback(mut self, script: Script) -> ScriptRouter {
+        self.fallback = Some(script);
+        self
+    }
+
+    /// Finds the script of the first route matching path
+    pub fn find(&self, path: &str) -> Option<(&Script, RouteMatch)> {
+        self.routes
+            .iter()
+            .find_map(|(route, script)| route.matches(path).map(|m| (script, m)))
+    }
+
+    pub async fn serve<B, W>(
+        &self,
+        mut req: Request<B>,
+        remote: SocketAddr,
+        error_writer: W,
+    ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>
+    where
+        B: Body<Data = Bytes> + Send + Unpin + 'static,
+        <B as Body>::Error: Into<BoxError> + Sync + Send,
+        W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
+    {
+        match self.find(req.uri().path()) {
+            Some((script, route_match)) => {
+                req.extensions_mut().insert(route_match);
+                script.serve(req, remote, error_writer).await
+            }
+            None => match &self.fallback {
+                Some(script) => script.serve(req, remote, error_writer).await,
+                None => Ok(get_error_response(
+                    StatusCode::NOT_FOUND,
+                    format!("No script for {}", req.uri().path()),
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_route() {
+        let route = Route::Prefix("/app/".to_string());
+        let m = route.matches("/app/users/1").unwrap();
+        assert_eq!(m.script_name, "/app");
+        assert_eq!(m.path_info, "/users/1");
+        assert_eq!(route.matches("/app").unwrap().path_info, "");
+        assert!(route.matches("/application").is_none());
+        assert_eq!(
+            Route::Prefix("/".to_string())
+                .matches("/any")
+                .unwrap()
+                .path_info,
+            "/any"
+        );
+    }
+
+    #[test]
+    fn regex_route() {
+        let route = Route::Regex(Regex::new(r"^/users/(?P<USER_ID>\d+)").unwrap());
+        let m = route.matches("/users/42/profile").unwrap();
+        assert_eq!(m.script_name, "/users/42");
+        assert_eq!(m.path_info, "/profile");
+        assert_eq!(m.env, vec![("USER_ID".to_string(), "42".to_string())]);
+        assert!(route.matches("/users/me").is_none());
+        let unanchored = Route::Regex(Regex::new(r"/users").unwrap());
+        assert!(unanchored.matches("/api/users").is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use std::path::{Path, PathBuf};
+
+/// Sandbox of a script process, applied in the child before exec.
+///
+/// Landlock restricts the filesystem to reading and executing the
+/// directory of the script, its working directory and the read-only paths.
+/// A seccomp filter makes dangerous system calls (ptrace, mount, module
+/// loading, raw sockets...) fail with `EPERM`. Both work without root, the
+/// script gets `no_new_privs` and cannot gain privileges through setuid
+/// executables.
+///
+/// Only supported on Linux x86_64 and aarch64 with Landlock enabled, the
+/// script cannot be spawned elsewhere.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Paths the script can read and execute, such as its interpreter and
+    /// the shared libraries (`/usr`, `/lib`...). Directories include their
+    /// content
+    pub read_only: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Opens the allowed paths and builds the filter in the server, the
+    /// child only has to apply them.
+    pub(crate) fn prepare(&self, dirs: &[&Path]) -> std::io::Result<PreparedSandbox> {
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        {
+            Ok(PreparedSandbox {
+                ruleset: linux::ruleset(
+                    dirs.iter()
+                        .copied()
+                        .chain(self.read_only.iter().map(PathBuf::as_path)),
+                )?,
+                filter: linux::filter(),
+            })
+        }
+        #[cfg(not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )))]
+        {
+            let _ = dirs;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "sandbox is only supported on Linux x86_64 and aarch64",
+            ))
+        }
+    }
+}
+
+/// Landlock ruleset and seccomp filter of a script.
+pub(crate) struct PreparedSandbox {
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    ruleset: std::os::fd::OwnedFd,
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    filter: Vec<libc::sock_filter>,
+    #[cfg(not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )))]
+    never: std::convert::Infallible,
+}
+
+impl PreparedSandbox {
+    /// Restricts the current process. Called between fork and exec, so it
+    /// only makes async-signal-safe calls.
+    pub(crate) fn apply(&self) -> std::io::Result<()> {
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        {
+            use std::os::fd::AsRawFd;
+
+            // SAFETY: plain system call on the current process
+            if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // SAFETY: plain system call with a valid file descriptor
+            let restricted = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_restrict_self,
+                    self.ruleset.as_raw_fd(),
+                    0,
+                )
+            };
+            if restricted != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let program = libc::sock_fprog {
+                len: self.filter.len() as libc::c_ushort,
+                filter: self.filter.as_ptr() as *mut libc::sock_filter,
+            };
+            // SAFETY: program points to the filter, which outlives the call
+            let filtered = unsafe {
+                libc::syscall(
+                    libc::SYS_seccomp,
+                    libc::SECCOMP_SET_MODE_FILTER,
+                    0,
+                    &program as *const libc::sock_fprog,
+                )
+            };
+            if filtered != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )))]
+        match self.never {}
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod linux {
+    use std::{
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::ffi::OsStrExt,
+        },
+        path::Path,
+    };
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// Rights of the first Landlock ABI, from execute to make symlink
+    const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+    const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Ruleset handling every filesystem right known by the kernel, and
+    /// allowing to read and execute beneath paths
+    pub(super) fn ruleset<'a, I>(paths: I) -> std::io::Result<OwnedFd>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        // SAFETY: a null attribute with the version flag only queries the ABI
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut handled_access_fs = ACCESS_FS_V1;
+        if abi >= 2 {
+            handled_access_fs |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled_access_fs |= ACCESS_FS_TRUNCATE;
+        }
+        if abi >= 5 {
+            handled_access_fs |= ACCESS_FS_IOCTL_DEV;
+        }
+
+        let attr = RulesetAttr { handled_access_fs };
+        // SAFETY: attr is valid for its size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: fd is a new file descriptor owned by nobody else
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        for path in paths {
+            let parent = open_path(path)?;
+            let metadata = std::fs::metadata(path)?;
+            let allowed_access = if metadata.is_dir() {
+                ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR
+            } else {
+                ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE
+            };
+            let rule = PathBeneathAttr {
+                allowed_access,
+                parent_fd: parent.as_raw_fd(),
+            };
+            // SAFETY: rule is valid for the call
+            let added = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            if added != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(ruleset)
+    }
+
+    fn open_path(path: &Path) -> std::io::Result<OwnedFd> {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: path is a valid C string
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: fd is a new file descriptor owned by nobody else
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Offsets in `seccomp_data`, the arguments are read by their low
+    /// 32 bits on these little-endian architectures
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+    const ARG0_OFFSET: u32 = 16;
+    const ARG1_OFFSET: u32 = 24;
+
+    const DENY: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    /// System calls failing with `EPERM`
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_iopl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ioperm,
+    ];
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Seccomp filter killing the process on foreign architectures, denying
+    /// the [`DENIED`] system calls and raw or packet sockets
+    pub(super) fn filter() -> Vec<libc::sock_filter> {
+        let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+        let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+        let ret = libc::BPF_RET | libc::BPF_K;
+
+        let mut filter = vec![
+            stmt(load, ARCH_OFFSET),
+            jump(jeq, AUDIT_ARCH, 1, 0),
+            stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(load, NR_OFFSET),
+        ];
+        // x32 system calls of x86_64 have their own numbers
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                0x4000_0000,
+                0,
+                1,
+            ),
+            stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for nr in DENIED {
+            filter.extend([jump(jeq, *nr as u32, 0, 1), stmt(ret, DENY)]);
+        }
+        filter.extend([
+            jump(jeq, libc::SYS_socket as u32, 1, 0),
+            stmt(ret, libc::SECCOMP_RET_ALLOW),
+            stmt(load, ARG0_OFFSET),
+            jump(jeq, libc::AF_PACKET as u32, 0, 1),
+            stmt(ret, DENY),
+            stmt(load, ARG1_OFFSET),
+            // Without the SOCK_NONBLOCK and SOCK_CLOEXEC flags
+            stmt(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, 0xf),
+            jump(jeq, libc::SOCK_RAW as u32, 0, 1),
+            stmt(ret, DENY),
+            stmt(ret, libc::SECCOMP_RET_ALLOW),
+        ]);
+        filter
+    }
+}
@@ -0,0 +1,132 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{body::Body, Request, Response};
+use log::debug;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::scgi::encode_header;
+
+use super::{
+    backend::{BackendReader, BackendWriter, OutputSender},
+    BackendAddress, BoxError, CgiServerError, Script,
+};
+
+/// Long-running application speaking SCGI.
+///
+/// The request gets the meta-variables of a CGI script, without the
+/// variables of the server process, and the path of the script is given as
+/// `SCRIPT_FILENAME`. A connection is opened for each request. Request
+/// bodies without Content-Length are answered with 411 unless spooling is
+/// enabled. The application has no stderr, error_writer and the stderr
+/// sink of the script are not used.
+#[derive(Debug, Clone)]
+pub struct ScgiBackend {
+    /// Address of the application
+    pub address: BackendAddress,
+
+    /// Settings of the requests. The script is not run, its path and
+    /// interpreters only set meta-variables
+    pub script: Script,
+}
+
+impl ScgiBackend {
+    pub async fn serve<B, W>(
+        &self,
+        req: Request<B>,
+        remote: SocketAddr,
+        _error_writer: W,
+    ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>
+    where
+        B: Body<Data = Bytes> + Send + Unpin + 'static,
+        <B as Body>::Error: Into<BoxError> + Sync + Send,
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let req = req.map(|body| body.map_err(Into::into).boxed_unsync());
+        let mut env = self.script.meta_variables(&req, remote);
+        // SCGI needs the length of the body before it, only spooling gives
+        // the length of a streamed body
+        let length_known = env.contains_key("CONTENT_LENGTH") || req.body().is_end_stream();
+        let result = async {
+            if !length_known && self.script.spool.is_none() {
+                return Err(CgiServerError::LengthRequired);
+            }
+            let stdin = self.script.script_stdin(req, &mut env).await?;
+            let content_length = match env
+                .get("CONTENT_LENGTH")
+                .and_then(|len| len.parse::<u64>().ok())
+            {
+                Some(len) => len,
+                None if length_known => 0,
+                None => return Err(CgiServerError::LengthRequired),
+            };
+            let (reader, mut writer) = self.address.connect().await?;
+
+            let header = encode_header(
+                &content_length.to_string(),
+                env.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+            );
+            writer
+                .write_all(&header)
+                .await
+                .map_err(CgiServerError::Backend)?;
+
+            // The connection is kept open until the end of the response
+            let (writer_tx, writer_rx) = oneshot::channel();
+            let mut stdin = stdin.into_stream();
+            tokio::spawn(async move {
+                // Exactly CONTENT_LENGTH bytes are sent
+                let written = async {
+                    let mut remaining = content_length;
+                    while remaining > 0 {
+                        let Some(chunk) = stdin.next().await else {
+                            break;
+                        };
+                        let chunk = chunk?;
+                        let len = remaining.min(chunk.len() as u64);
+                        writer.write_all(&chunk[..len as usize]).await?;
+                        remaining -= len;
+                    }
+                    writer.flush().await
+                }
+                .await;
+                if let Err(err) = written {
+                    debug!("Cannot write request body to SCGI backend: {}", err);
+                }
+                let _ = writer_tx.send(writer);
+            });
+
+            let (output_tx, output_rx) = mpsc::channel(16);
+            tokio::spawn(read_output(reader, output_tx, writer_rx));
+            self.script.backend_response(output_rx).await
+        }
+        .await;
+        Ok(match result {
+            Ok(response) => response,
+            Err(err) => self.script.error_response(err),
+        })
+    }
+}
+
+/// Sends the output of the application to the response
+async fn read_output(
+    reader: BackendReader,
+    output: OutputSender,
+    writer: oneshot::Receiver<BackendWriter>,
+) {
+    let _writer = writer;
+    let mut chunks = ReaderStream::new(reader);
+    while let Some(chunk) = chunks.next().await {
+        let sent = output.send(chunk).await;
+        if sent.is_err() {
+            // The response has been dropped
+            return;
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Body, Request, Response};
+use tokio::io::{stderr, AsyncWrite, Stderr};
+
+use super::{
+    BoxError, FastCgiBackend, FastCgiWrap, ScgiBackend, Script, ScriptAlias, ScriptRouter,
+};
+
+/// Address of the client, read from the request extensions by [`ScriptService`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectInfo(pub SocketAddr);
+
+pub type ScriptFuture = Pin<
+    Box<dyn Future<Output = Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>> + Send>,
+>;
+
+/// Service running a script, or the script of a router, for each request.
+///
+/// It implements both [`tower_service::Service`] and [`hyper::service::Service`].
+/// The address of the client is read from the [`ConnectInfo`] extension of
+/// the request, `0.0.0.0:0` is used if it is missing.
+#[derive(Debug, Clone)]
+pub struct ScriptService<W = ClonableStderr> {
+    target: Arc<Target>,
+    error_writer: W,
+}
+
+#[derive(Debug)]
+enum Target {
+    Script(Script),
+    Router(ScriptRouter),
+    Alias(ScriptAlias),
+    FastCgi(FastCgiBackend),
+    Scgi(ScgiBackend),
+    Wrap(FastCgiWrap),
+}
+
+impl Script {
+    /// Service running this script, its stderr is written to the stderr of
+    /// the server
+    pub fn service(&self) -> ScriptService {
+        self.service_with_writer(ClonableStderr::new())
+    }
+
+    /// Service running this script, its stderr is written to error_writer
+    pub fn service_with_writer<W>(&self, error_writer: W) -> ScriptService<W> {
+        ScriptService {
+            target: Arc::new(Target::Script(self.clone())),
+            error_writer,
+        }
+    }
+}
+
+impl ScriptRouter {
+    /// Service routing to the scripts, their stderr is written to the
+    /// stderr of the server
+    pub fn service(&self) -> ScriptService {
+        self.service_with_writer(ClonableStderr::new())
+    }
+
+    /// Service routing to the scripts, their stderr is written to
+    /// error_writer
+    pub fn service_with_writer<W>(&self, error_writer: W) -> ScriptService<W> {
+        ScriptService {
+            target: Arc::new(Target::Router(self.clone())),
+            error_writer,
+        }
+    }
+}
+
+impl ScriptAlias {
+    /// Service running the scripts of the directory, their stderr is written
+    /// to the stderr of the server
+    pub fn service(&self) -> ScriptService {
+        self.service_with_writer(ClonableStderr::new())
+    }
+
+    /// Service running the scripts of the directory, their stderr is written
+    /// to error_writer
+    pub fn service_with_writer<W>(&self, error_writer: W) -> ScriptService<W> {
+        ScriptService {
+            target: Arc::new(Target::Alias(self.clone())),
+            error_writer,
+        }
+    }
+}
+
+impl FastCgiBackend {
+    /// Service forwarding the requests to the application, its stderr
+    /// records are written to the stderr of the server
+    pub fn service(&self) -> ScriptService {
+        self.service_with_writer(ClonableStderr::new())
+    }
+
+    /// Service forwarding the requests to the application, its stderr
+    /// records are written to error_writer
+    pub fn service_with_writer<W>(&self, error_writer: W) -> ScriptService<W> {
+        ScriptService {
+            target: Arc::new(Target::FastCgi(self.clone())),
+            error_writer,
+        }
+    }
+}
+
+impl ScgiBackend {
+    /// Service forwarding the requests to the application
+    pub fn service(&self) -> ScriptService {
+        self.service_with_writer(ClonableStderr::new())
+    }
+
+    /// Service forwarding the requests to the application, error_writer is
+    /// not used
+    pub fn service_with_writer<W>(&self, error_writer: W) -> ScriptService<W> {
+        ScriptService {
+            target: Arc::new(Target::Scgi(self.clone())),
+            error_writer,
+        }
+    }
+}
+
+impl FastCgiWrap {
+    /// Service running the scripts named by the FastCGI requests, their
+    /// stderr is written to the stderr of the server
+    pub fn service(&self) -> ScriptService {
+        self.service_with_writer(ClonableStderr::new())
+    }
+
+    /// Service running the scripts named by the FastCGI requests, their
+    /// stderr is written to error_writer
+    pub fn service_with_writer<W>(&self, error_writer: W) -> ScriptService<W> {
+        ScriptService {
+            target: Arc::new(Target::Wrap(self.clone())),
+            error_writer,
+        }
+    }
+}
+
+impl<B, W> tower_service::Service<Request<B>> for ScriptService<W>
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    <B as Body>::Error: Into<BoxError> + Sync + Send,
+    W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
+{
+    type Response = Response<BoxBody<Bytes, std::io::Error>>;
+    type Error = Infallible;
+    type Future = ScriptFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        hyper::service::Service::call(self, req)
+    }
+}
+
+impl<B, W> hyper::service::Service<Request<B>> for ScriptService<W>
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    <B as Body>::Error: Into<BoxError> + Sync + Send,
+    W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
+{
+    type Response = Response<BoxBody<Bytes, std::io::Error>>;
+    type Error = Infallible;
+    type Future = ScriptFuture;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let target = self.target.clone();
+        let error_writer = self.error_writer.clone();
+        let remote = req
+            .extensions()
+            .get::<ConnectInfo>()
+            .map_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), |info| info.0);
+        Box::pin(async move {
+            match target.as_ref() {
+                Target::Script(script) => script.serve(req, remote, error_writer).await,
+                Target::Router(router) => router.serve(req, remote, error_writer).await,
+                Target::Alias(alias) => alias.serve(req, remote, error_writer).await,
+                Target::FastCgi(backend) => backend.serve(req, remote, error_writer).await,
+                Target::Scgi(backend) => backend.serve(req, remote, error_writer).await,
+                Target::Wrap(wrap) => wrap.serve(req, remote, error_writer).await,
+            }
+        })
+    }
+}
+
+/// Stderr of the server, each clone writing to it.
+#[derive(Debug)]
+pub struct ClonableStderr(Stderr);
+
+impl ClonableStderr {
+    pub fn new() -> ClonableStderr {
+        ClonableStderr(stderr())
+    }
+}
+
+impl Default for ClonableStderr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for ClonableStderr {
+    fn clone(&self) -> Self {
+        Self(stderr())
+    }
+}
+
+impl AsyncWrite for ClonableStderr {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
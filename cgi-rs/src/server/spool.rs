@@ -0,0 +1,186 @@
+use std::{io::SeekFrom, path::PathBuf};
+
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use http_body_util::BodyExt;
+use hyper::body::Body;
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+use super::{process::ScriptStdin, BoxError};
+
+/// Configuration of request body spooling.
+///
+/// Bodies without a known length (chunked requests, HTTP/2 streams) are read
+/// entirely before the script is spawned, so that `CONTENT_LENGTH` can be set.
+#[derive(Debug, Clone)]
+pub struct SpoolConfig {
+    /// Number of bytes kept in memory before spilling to a temporary file
+    pub memory_threshold: usize,
+
+    /// Maximum size of a spooled body. Larger bodies are rejected with 413.
+    pub max_size: u64,
+
+    /// Directory of the temporary files.
+    /// If None, the system temporary directory is used
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        SpoolConfig {
+            memory_threshold: 1024 * 1024,
+            max_size: 100 * 1024 * 1024,
+            temp_dir: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum SpoolError {
+    /// Body is larger than `SpoolConfig::max_size`
+    TooLarge,
+    /// Temporary file cannot be written
+    Io(std::io::Error),
+    /// Request body cannot be read
+    Body(BoxError),
+}
+
+enum Storage {
+    Memory(Vec<Bytes>),
+    File(File),
+}
+
+/// A request body fully read, either in memory or in a temporary file.
+pub(crate) struct SpooledBody {
+    storage: Storage,
+    len: u64,
+}
+
+impl SpooledBody {
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Standard input of the script. A spilled body is given as a seekable
+    /// file rewound to its beginning.
+    pub(crate) async fn into_stdin(self) -> std::io::Result<ScriptStdin> {
+        match self.storage {
+            Storage::Memory(chunks) => Ok(ScriptStdin::Stream(
+                stream::iter(chunks.into_iter().map(Ok)).boxed(),
+            )),
+            Storage::File(mut file) => {
+                file.seek(SeekFrom::Start(0)).await?;
+                Ok(ScriptStdin::File(file.into_std().await))
+            }
+        }
+    }
+}
+
+pub(crate) async fn spool<B>(mut body: B, config: &SpoolConfig) -> Result<SpooledBody, SpoolError>
+where
+    B: Body<Data = Bytes> + Unpin,
+    <B as Body>::Error: Into<BoxError>,
+{
+    let mut storage = Storage::Memory(Vec::new());
+    let mut len: u64 = 0;
+
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|err| SpoolError::Body(err.into()))?;
+        let data = match frame.into_data() {
+            Ok(data) => data,
+            Err(_trailers) => continue,
+        };
+        len += data.len() as u64;
+        if len > config.max_size {
+            return Err(SpoolError::TooLarge);
+        }
+        match &mut storage {
+            Storage::Memory(chunks) => {
+                chunks.push(data);
+                if len > config.memory_threshold as u64 {
+                    let mut file = create_temp_file(config).map_err(SpoolError::Io)?;
+                    for chunk in chunks.iter() {
+                        file.write_all(chunk).await.map_err(SpoolError::Io)?;
+                    }
+                    storage = Storage::File(file);
+                }
+            }
+            Storage::File(file) => {
+                file.write_all(&data).await.map_err(SpoolError::Io)?;
+            }
+        }
+    }
+
+    if let Storage::File(file) = &mut storage {
+        file.flush().await.map_err(SpoolError::Io)?;
+    }
+
+    Ok(SpooledBody { storage, len })
+}
+
+fn create_temp_file(config: &SpoolConfig) -> std::io::Result<File> {
+    let file = match &config.temp_dir {
+        Some(dir) => tempfile::tempfile_in(dir)?,
+        None => tempfile::tempfile()?,
+    };
+    Ok(File::from_std(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use http_body_util::Full;
+
+    async fn collect(spooled: SpooledBody) -> Vec<u8> {
+        match spooled.into_stdin().await.unwrap() {
+            ScriptStdin::Stream(stream) => stream
+                .map_ok(|bytes| bytes.to_vec())
+                .try_concat()
+                .await
+                .unwrap(),
+            ScriptStdin::File(mut file) => {
+                let mut content = Vec::new();
+                std::io::Read::read_to_end(&mut file, &mut content).unwrap();
+                content
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn spool_in_memory() {
+        let config = SpoolConfig::default();
+        let spooled = spool(Full::new(Bytes::from("hello")), &config)
+            .await
+            .unwrap();
+        assert_eq!(spooled.len(), 5);
+        assert!(matches!(spooled.storage, Storage::Memory(_)));
+        assert_eq!(collect(spooled).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn spool_to_file() {
+        let config = SpoolConfig {
+            memory_threshold: 2,
+            ..SpoolConfig::default()
+        };
+        let spooled = spool(Full::new(Bytes::from("hello")), &config)
+            .await
+            .unwrap();
+        assert!(matches!(spooled.storage, Storage::File(_)));
+        assert_eq!(collect(spooled).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn spool_too_large() {
+        let config = SpoolConfig {
+            max_size: 4,
+            ..SpoolConfig::default()
+        };
+        let res = spool(Full::new(Bytes::from("hello")), &config).await;
+        assert!(matches!(res, Err(SpoolError::TooLarge)));
+    }
+}
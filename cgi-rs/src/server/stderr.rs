@@ -0,0 +1,394 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+};
+
+use log::Level;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+/// Max length of a stderr line, longer lines are split
+pub const MAX_STDERR_LINE: u64 = 8 * 1024;
+
+/// Lines waiting to be written by a [`FileSink`]. Further lines are dropped
+pub const FILE_SINK_CAPACITY: usize = 4096;
+
+/// Request that a stderr line comes from.
+#[derive(Debug, Clone)]
+pub struct StderrMeta {
+    /// Value of the `X-Request-Id` header, or a number unique to the process
+    pub request_id: String,
+    /// Path to the CGI executable
+    pub script: PathBuf,
+    /// Address of the client
+    pub remote: SocketAddr,
+    /// Pid of the script process
+    pub pid: Option<u32>,
+}
+
+/// Destination of the stderr lines of scripts.
+///
+/// Lines are given without their trailing newline.
+pub trait StderrSink: Send + Sync {
+    fn write_line(&self, meta: &StderrMeta, line: &[u8]);
+}
+
+impl Debug for dyn StderrSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StderrSink")
+    }
+}
+
+/// Sink logging stderr lines with the `log` crate, with target `cgi_rs::stderr`.
+#[derive(Debug, Clone)]
+pub struct LogSink {
+    pub level: Level,
+}
+
+impl Default for LogSink {
+    fn default() -> Self {
+        LogSink { level: Level::Warn }
+    }
+}
+
+impl StderrSink for LogSink {
+    fn write_line(&self, meta: &StderrMeta, line: &[u8]) {
+        log::log!(
+            target: "cgi_rs::stderr",
+            self.level,
+            "[{}] {} {} (pid {}): {}",
+            meta.request_id,
+            meta.remote,
+            meta.script.to_string_lossy(),
+            format_pid(meta.pid),
+            String::from_utf8_lossy(line)
+        );
+    }
+}
+
+/// Sink appending stderr lines to files, prefixed by their request.
+///
+/// Files are written by a dedicated thread, so that scripts are not slowed
+/// down by the disk. Lines are dropped while [`FILE_SINK_CAPACITY`] lines
+/// are already waiting for the thread.
+#[derive(Debug)]
+pub struct FileSink {
+    sender: mpsc::SyncSender<FileRecord>,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+enum FileRecord {
+    Line { script: PathBuf, record: Vec<u8> },
+    Flush(mpsc::SyncSender<()>),
+}
+
+/// Files written by a [`FileSink`]
+enum FileTarget {
+    Shared(File),
+    PerScript {
+        dir: PathBuf,
+        files: HashMap<PathBuf, File>,
+    },
+}
+
+impl FileSink {
+    /// Opens the file in append mode, creating it if needed. It receives the
+    /// lines of all scripts
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<FileSink> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        FileSink::spawn(FileTarget::Shared(file), FILE_SINK_CAPACITY)
+    }
+
+    /// Writes the lines of each script to its own file in dir, named after
+    /// the file name of the script with a `.log` extension. Scripts with the
+    /// same file name share a file
+    pub fn per_script(dir: impl Into<PathBuf>) -> std::io::Result<FileSink> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        FileSink::spawn(
+            FileTarget::PerScript {
+                dir,
+                files: HashMap::new(),
+            },
+            FILE_SINK_CAPACITY,
+        )
+    }
+
+    fn spawn(mut target: FileTarget, capacity: usize) -> std::io::Result<FileSink> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        std::thread::Builder::new()
+            .name("cgi-stderr-file".to_string())
+            .spawn(move || {
+                // Ends once the sink is dropped
+                for record in receiver {
+                    match record {
+                        FileRecord::Line { script, record } => target.write(&script, &record),
+                        FileRecord::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(FileSink {
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Number of lines dropped because the writing thread was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until the lines already received are written
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::sync_channel(1);
+        if self.sender.send(FileRecord::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl FileTarget {
+    fn write(&mut self, script: &Path, record: &[u8]) {
+        let written = match self {
+            FileTarget::Shared(file) => file.write_all(record),
+            FileTarget::PerScript { dir, files } => {
+                let file = match files.get_mut(script) {
+                    Some(file) => Ok(file),
+                    None => {
+                        let mut name = script.file_name().unwrap_or_default().to_os_string();
+                        name.push(".log");
+                        OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(dir.join(name))
+                            .map(|file| files.entry(script.to_path_buf()).or_insert(file))
+                    }
+                };
+                file.and_then(|file| file.write_all(record))
+            }
+        };
+        if let Err(err) = written {
+            log::debug!("Cannot write script stderr to file: {}", err);
+        }
+    }
+}
+
+impl StderrSink for FileSink {
+    fn write_line(&self, meta: &StderrMeta, line: &[u8]) {
+        let mut record = format!(
+            "[{}] {} {} (pid {}): ",
+            meta.request_id,
+            meta.remote,
+            meta.script.to_string_lossy(),
+            format_pid(meta.pid)
+        )
+        .into_bytes();
+        record.extend_from_slice(line);
+        record.push(b'\n');
+        let line = FileRecord::Line {
+            script: meta.script.clone(),
+            record,
+        };
+        if let Err(mpsc::TrySendError::Full(_)) = self.sender.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stderr line kept by a [`RingBufferSink`].
+#[derive(Debug, Clone)]
+pub struct StderrLine {
+    pub meta: StderrMeta,
+    pub line: Vec<u8>,
+}
+
+/// Sink keeping the last stderr lines in memory.
+#[derive(Debug, Clone)]
+pub struct RingBufferSink {
+    capacity: usize,
+    lines: Arc<Mutex<VecDeque<StderrLine>>>,
+}
+
+impl RingBufferSink {
+    /// Keeps at most capacity lines, older lines are dropped
+    pub fn new(capacity: usize) -> RingBufferSink {
+        RingBufferSink {
+            capacity,
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Lines currently kept, oldest first
+    pub fn lines(&self) -> Vec<StderrLine> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Lines of a request, oldest first
+    pub fn lines_of(&self, request_id: &str) -> Vec<StderrLine> {
+        self.lock()
+            .iter()
+            .filter(|line| line.meta.request_id == request_id)
+            .cloned()
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<StderrLine>> {
+        self.lines
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl StderrSink for RingBufferSink {
+    fn write_line(&self, meta: &StderrMeta, line: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lock();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(StderrLine {
+            meta: meta.clone(),
+            line: line.to_vec(),
+        });
+    }
+}
+
+fn format_pid(pid: Option<u32>) -> String {
+    pid.map_or_else(|| "-".to_string(), |pid| pid.to_string())
+}
+
+/// Reads stderr of a script line by line and writes the lines to the sink.
+pub(crate) async fn forward_lines<R>(stderr: R, sink: &dyn StderrSink, meta: &StderrMeta)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(stderr);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut reader)
+            .take(MAX_STDERR_LINE)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) => break,
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                }
+                sink.write_line(meta, &line);
+            }
+            Err(err) => {
+                log::debug!("Cannot read script stderr: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(request_id: &str) -> StderrMeta {
+        StderrMeta {
+            request_id: request_id.to_string(),
+            script: PathBuf::from("/cgi-bin/test.sh"),
+            remote: "127.0.0.1:1234".parse().unwrap(),
+            pid: Some(42),
+        }
+    }
+
+    #[tokio::test]
+    async fn split_lines() {
+        let sink = RingBufferSink::new(4);
+        let long = "x".repeat(MAX_STDERR_LINE as usize + 1);
+        let input = format!("first\r\nsecond\n{}", long);
+        forward_lines(input.as_bytes(), &sink, &meta("1")).await;
+        let lines: Vec<Vec<u8>> = sink.lines().into_iter().map(|l| l.line).collect();
+        assert_eq!(
+            lines,
+            vec![
+                b"first".to_vec(),
+                b"second".to_vec(),
+                vec![b'x'; MAX_STDERR_LINE as usize],
+                vec![b'x']
+            ]
+        );
+    }
+
+    #[test]
+    fn file_per_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = FileSink::per_script(dir.path()).unwrap();
+        let mut other = meta("2");
+        other.script = PathBuf::from("/cgi-bin/other.sh");
+        sink.write_line(&meta("1"), b"a");
+        sink.write_line(&other, b"b");
+        sink.write_line(&meta("3"), b"c");
+        sink.flush();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("test.sh.log")).unwrap(),
+            "[1] 127.0.0.1:1234 /cgi-bin/test.sh (pid 42): a\n\
+             [3] 127.0.0.1:1234 /cgi-bin/test.sh (pid 42): c\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("other.sh.log")).unwrap(),
+            "[2] 127.0.0.1:1234 /cgi-bin/other.sh (pid 42): b\n"
+        );
+    }
+
+    #[test]
+    fn file_sink_drops_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = FileTarget::PerScript {
+            dir: dir.path().to_path_buf(),
+            files: HashMap::new(),
+        };
+        let sink = FileSink::spawn(target, 1).unwrap();
+        // The thread holds the flush until it is received, with a line queued behind it
+        let (done, wait) = mpsc::sync_channel(0);
+        sink.sender.send(FileRecord::Flush(done)).unwrap();
+        sink.sender
+            .send(FileRecord::Line {
+                script: PathBuf::from("/cgi-bin/test.sh"),
+                record: b"queued\n".to_vec(),
+            })
+            .unwrap();
+        sink.write_line(&meta("1"), b"a");
+        sink.write_line(&meta("2"), b"b");
+        assert_eq!(sink.dropped(), 2);
+        wait.recv().unwrap();
+        sink.flush();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("test.sh.log")).unwrap(),
+            "queued\n"
+        );
+    }
+
+    #[test]
+    fn ring_buffer_by_request() {
+        let sink = RingBufferSink::new(3);
+        sink.write_line(&meta("1"), b"a");
+        sink.write_line(&meta("2"), b"b");
+        sink.write_line(&meta("1"), b"c");
+        sink.write_line(&meta("1"), b"d");
+        let lines: Vec<Vec<u8>> = sink.lines_of("1").into_iter().map(|l| l.line).collect();
+        assert_eq!(lines, vec![b"c".to_vec(), b"d".to_vec()]);
+    }
+}
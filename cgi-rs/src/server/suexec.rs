@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::CgiServerError;
+
+/// User and groups running a script, with suEXEC-like checks of the script
+/// before it is run.
+///
+/// The script must live under one of the allowed directories, be owned by
+/// the target user, and neither it nor its directory may be writable by
+/// others. The server must run as root to switch to another user. Only
+/// supported on Unix, scripts are refused elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    /// User id of the script process, root is refused
+    pub uid: u32,
+
+    /// Group id of the script process, root is refused
+    pub gid: u32,
+
+    /// Supplementary groups of the script process
+    pub groups: Vec<u32>,
+
+    /// Directories the scripts must live under
+    pub allowed_dirs: Vec<PathBuf>,
+}
+
+impl RunAs {
+    /// Checks that path can be run as the target user. Refusals give a
+    /// 403, scripts that cannot be inspected a 500. Both are logged.
+    ///
+    /// Returns the canonical path of the script, which is the one to run:
+    /// the symbolic links of path may be changed once it is checked.
+    pub(crate) async fn check(&self, path: &Path) -> Result<PathBuf, CgiServerError> {
+        let result = self.check_script(path).await;
+        if let Err(err) = &result {
+            warn!("suexec: {}", err);
+        }
+        result
+    }
+
+    #[cfg(unix)]
+    async fn check_script(&self, path: &Path) -> Result<PathBuf, CgiServerError> {
+        use std::os::unix::fs::MetadataExt;
+
+        let refused = |reason: &str| CgiServerError::RunAsRefused {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        };
+        let failed = |source| CgiServerError::RunAsCheck {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        if self.uid == 0 {
+            return Err(refused("target user is root"));
+        }
+        if self.gid == 0 {
+            return Err(refused("target group is root"));
+        }
+
+        let canonical = tokio::fs::canonicalize(path).await.map_err(failed)?;
+        let mut allowed = false;
+        for dir in &self.allowed_dirs {
+            if let Ok(dir) = tokio::fs::canonicalize(dir).await {
+                allowed |= canonical.starts_with(dir);
+            }
+        }
+        if !allowed {
+            return Err(refused("script is outside of the allowed directories"));
+        }
+
+        let metadata = tokio::fs::metadata(&canonical).await.map_err(failed)?;
+        if !metadata.is_file() {
+            return Err(refused("script is not a regular file"));
+        }
+        if metadata.mode() & 0o002 != 0 {
+            return Err(refused("script is writable by others"));
+        }
+        if metadata.mode() & 0o6000 != 0 {
+            return Err(refused("script is setuid or setgid"));
+        }
+        if metadata.uid() != self.uid {
+            return Err(refused("script is not owned by the target user"));
+        }
+        if let Some(dir) = canonical.parent() {
+            let metadata = tokio::fs::metadata(dir).await.map_err(failed)?;
+            if metadata.mode() & 0o002 != 0 {
+                return Err(refused("directory of the script is writable by others"));
+            }
+        }
+        Ok(canonical)
+    }
+
+    #[cfg(not(unix))]
+    async fn check_script(&self, path: &Path) -> Result<PathBuf, CgiServerError> {
+        Err(CgiServerError::RunAsRefused {
+            path: path.to_path_buf(),
+            reason: "running as another user is only supported on Unix".to_string(),
+        })
+    }
+
+    /// Switches the current process to the target user. Called between
+    /// fork and exec, so it only makes async-signal-safe calls.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self) -> std::io::Result<()> {
+        // SAFETY: groups is a valid slice for the call
+        if unsafe { libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: plain system calls on the current process
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: plain system calls on the current process
+        if unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn check() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cgi-bin");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script = dir.join("test.cgi");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let owner = std::fs::metadata(&script).unwrap().uid();
+        let run_as = |uid| RunAs {
+            uid,
+            gid: 1000,
+            groups: Vec::new(),
+            allowed_dirs: vec![dir.clone()],
+        };
+        let reason = |err: CgiServerError| match err {
+            CgiServerError::RunAsRefused { reason, .. } => reason,
+            err => panic!("unexpected error {}", err),
+        };
+
+        if owner != 0 {
+            let link = dir.join("link.cgi");
+            std::os::unix::fs::symlink(&script, &link).unwrap();
+            assert_eq!(
+                run_as(owner).check(&link).await.unwrap(),
+                std::fs::canonicalize(&script).unwrap()
+            );
+        }
+        assert_eq!(
+            reason(run_as(0).check(&script).await.unwrap_err()),
+            "target user is root"
+        );
+        assert_eq!(
+            reason(run_as(owner + 1).check(&script).await.unwrap_err()),
+            "script is not owned by the target user"
+        );
+
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o757)).unwrap();
+        assert_eq!(
+            reason(run_as(owner + 1).check(&script).await.unwrap_err()),
+            "script is writable by others"
+        );
+
+        let outside = tmp.path().join("outside.cgi");
+        std::fs::write(&outside, "#!/bin/sh\n").unwrap();
+        assert_eq!(
+            reason(run_as(owner + 1).check(&outside).await.unwrap_err()),
+            "script is outside of the allowed directories"
+        );
+
+        let err = run_as(owner + 1)
+            .check(&dir.join("missing.cgi"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code().as_u16(), 500);
+    }
+}
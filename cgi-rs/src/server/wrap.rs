@@ -0,0 +1,465 @@
+use std::{convert::Infallible, fmt::Display, io, net::SocketAddr, path::PathBuf, pin::pin};
+
+use bytes::Bytes;
+use futures::future::poll_fn;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{body::Body, Request, Response, StatusCode};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::fastcgi::{
+    decode_pairs, decode_params, encode_pairs, end_request, parse_begin_request, read_record,
+    write_record, write_stream, FastCgiBody, ABORT_REQUEST, BEGIN_REQUEST, CANT_MPX_CONN,
+    END_REQUEST, GET_VALUES, GET_VALUES_RESULT, KEEP_CONN, PARAMS, REQUEST_COMPLETE, RESPONDER,
+    STDIN, STDOUT, UNKNOWN_ROLE, UNKNOWN_TYPE,
+};
+
+use super::{BoxError, CgiServerError, ConnectInfo, RouteMatch, Script};
+
+/// Params of a FastCGI request, read from the request extensions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastCgiParams(pub Vec<(String, String)>);
+
+impl FastCgiParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Params given to the script besides `HTTP_` ones: the meta-variables of
+/// RFC 3875 and the ones commonly set by web servers
+const FORWARDED_PARAMS: &[&str] = &[
+    "AUTH_TYPE",
+    "CONTENT_LENGTH",
+    "CONTENT_TYPE",
+    "CONTEXT_DOCUMENT_ROOT",
+    "CONTEXT_PREFIX",
+    "DOCUMENT_ROOT",
+    "DOCUMENT_URI",
+    "GATEWAY_INTERFACE",
+    "HTTPS",
+    "PATH_INFO",
+    "PATH_TRANSLATED",
+    "QUERY_STRING",
+    "REDIRECT_STATUS",
+    "REMOTE_ADDR",
+    "REMOTE_HOST",
+    "REMOTE_IDENT",
+    "REMOTE_PORT",
+    "REMOTE_USER",
+    "REQUEST_METHOD",
+    "REQUEST_SCHEME",
+    "REQUEST_URI",
+    "SCRIPT_FILENAME",
+    "SCRIPT_NAME",
+    "SERVER_ADDR",
+    "SERVER_ADMIN",
+    "SERVER_NAME",
+    "SERVER_PORT",
+    "SERVER_PROTOCOL",
+    "SERVER_SOFTWARE",
+];
+
+/// Whether a param of the web server is given to the script. Other params,
+/// such as PATH or LD_PRELOAD, could change how the script is run
+fn is_forwarded(name: &str) -> bool {
+    (name.starts_with("HTTP_") && name != "HTTP_PROXY") || FORWARDED_PARAMS.contains(&name)
+}
+
+/// Scripts named by the `SCRIPT_FILENAME` param of FastCGI requests, as
+/// fcgiwrap runs them.
+///
+/// The CGI meta-variables and `HTTP_` params of the request are given to
+/// the script, the other params are dropped.
+#[derive(Debug, Clone)]
+pub struct FastCgiWrap {
+    /// Settings of the scripts. Its path is replaced by `SCRIPT_FILENAME`,
+    /// and its root is ignored
+    pub script: Script,
+
+    /// Directory the scripts must be in. `SCRIPT_FILENAME` is resolved,
+    /// following symbolic links, and refused with 403 outside of it
+    pub allowed_root: PathBuf,
+}
+
+impl FastCgiWrap {
+    /// Picks the executable of the request. Files without an interpreter
+    /// must be executable.
+    async fn resolve(&self, params: &FastCgiParams) -> Result<PathBuf, CgiServerError> {
+        let filename = params
+            .get("SCRIPT_FILENAME")
+            .filter(|filename| !filename.is_empty())
+            .ok_or_else(|| CgiServerError::NotFound("SCRIPT_FILENAME".to_string()))?;
+        let path = tokio::fs::canonicalize(filename)
+            .await
+            .map_err(|_| CgiServerError::NotFound(filename.to_string()))?;
+        let root = tokio::fs::canonicalize(&self.allowed_root)
+            .await
+            .map_err(|_| CgiServerError::Forbidden(filename.to_string()))?;
+        if !path.starts_with(root) {
+            return Err(CgiServerError::Forbidden(filename.to_string()));
+        }
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|_| CgiServerError::NotFound(filename.to_string()))?;
+        if !self.script.can_run(&path, &metadata) {
+            return Err(CgiServerError::Forbidden(filename.to_string()));
+        }
+        Ok(path)
+    }
+
+    pub async fn serve<B, W>(
+        &self,
+        mut req: Request<B>,
+        remote: SocketAddr,
+        error_writer: W,
+    ) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Infallible>
+    where
+        B: Body<Data = Bytes> + Send + Unpin + 'static,
+        <B as Body>::Error: Into<BoxError> + Sync + Send,
+        W: AsyncWrite + Unpin + Send + Sync + Clone + 'static,
+    {
+        let params = req
+            .extensions()
+            .get::<FastCgiParams>()
+            .cloned()
+            .unwrap_or_default();
+        match self.resolve(&params).await {
+            Ok(path) => {
+                req.extensions_mut().insert(RouteMatch {
+                    script_name: params.get("SCRIPT_NAME").unwrap_or_default().to_string(),
+                    path_info: params.get("PATH_INFO").unwrap_or_default().to_string(),
+                    env: params
+                        .0
+                        .into_iter()
+                        .filter(|(name, _)| is_forwarded(name))
+                        .collect(),
+                });
+                let script = Script {
+                    path,
+                    ..self.script.clone()
+                };
+                script.serve(req, remote, error_writer).await
+            }
+            Err(err) => Ok(self.script.error_response(err)),
+        }
+    }
+}
+
+/// Serves the FastCGI requests of a connection from a web server.
+///
+/// Requests are handled one at a time. Their params are given in the
+/// [`FastCgiParams`] extension, and the address of the client, read from
+/// `REMOTE_ADDR` and `REMOTE_PORT`, in the [`ConnectInfo`] extension.
+pub async fn serve_fastcgi_connection<IO, S, B>(io: IO, mut service: S) -> io::Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    S: tower_service::Service<Request<FastCgiBody>, Response = Response<B>>,
+    S::Error: Display,
+    B: Body,
+    B::Data: AsRef<[u8]>,
+    B::Error: Display,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    loop {
+        let Some((request_id, flags)) = next_request(&mut reader, &mut writer).await? else {
+            return Ok(());
+        };
+        let Some(params) = read_params(&mut reader, &mut writer, request_id).await? else {
+            continue;
+        };
+
+        let content_length = params
+            .get("CONTENT_LENGTH")
+            .and_then(|length| length.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let (sender, receiver) = mpsc::channel(16);
+        let body = FastCgiBody::new(receiver, content_length == 0);
+        let request = fastcgi_request(params, body);
+
+        let respond = async {
+            match request {
+                Ok(req) => {
+                    let response = match poll_fn(|cx| service.poll_ready(cx)).await {
+                        Ok(()) => service.call(req).await,
+                        Err(err) => Err(err),
+                    };
+                    match response {
+                        Ok(response) => write_response(&mut writer, request_id, response).await,
+                        Err(err) => {
+                            debug!("FastCGI request failed: {}", err);
+                            write_error(&mut writer, request_id, StatusCode::INTERNAL_SERVER_ERROR)
+                                .await
+                        }
+                    }
+                }
+                Err(msg) => {
+                    debug!("Invalid FastCGI request: {}", msg);
+                    write_error(&mut writer, request_id, StatusCode::BAD_REQUEST).await
+                }
+            }
+        };
+        let (read, written) = tokio::join!(read_stdin(&mut reader, request_id, sender), respond);
+        written?;
+        read?;
+
+        if flags & KEEP_CONN == 0 {
+            return writer.shutdown().await;
+        }
+    }
+}
+
+/// Reads the records until the beginning of a responder request, answering
+/// management records. Returns None at the end of the connection.
+async fn next_request<R, W>(reader: &mut R, writer: &mut W) -> io::Result<Option<(u16, u8)>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let Some(record) = read_record(reader).await? else {
+            return Ok(None);
+        };
+        match record.kind {
+            BEGIN_REQUEST => {
+                let (role, flags) = parse_begin_request(&record.content)?;
+                if role == RESPONDER {
+                    return Ok(Some((record.request_id, flags)));
+                }
+                let content = end_request(0, UNKNOWN_ROLE);
+                write_record(writer, END_REQUEST, record.request_id, &content).await?;
+            }
+            GET_VALUES => {
+                let names = decode_pairs(&record.content)?;
+                let values = names
+                    .iter()
+                    .filter(|(name, _)| name == b"FCGI_MPXS_CONNS")
+                    .map(|(name, _)| (name.as_slice(), b"0".as_slice()));
+                write_record(writer, GET_VALUES_RESULT, 0, &encode_pairs(values)).await?;
+            }
+            kind if record.request_id == 0 => {
+                write_record(writer, UNKNOWN_TYPE, 0, &[kind, 0, 0, 0, 0, 0, 0, 0]).await?;
+            }
+            // Records of an ended request
+            _ => {}
+        }
+        writer.flush().await?;
+    }
+}
+
+/// Reads the params of a request. Returns None if the request is aborted.
+async fn read_params<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    request_id: u16,
+) -> io::Result<Option<FastCgiParams>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut data = Vec::new();
+    loop {
+        let record = read_record(reader).await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "FastCGI connection closed before the end of the params",
+            )
+        })?;
+        match record.kind {
+            PARAMS if record.request_id == request_id && record.content.is_empty() => break,
+            PARAMS if record.request_id == request_id => data.extend_from_slice(&record.content),
+            ABORT_REQUEST if record.request_id == request_id => {
+                let content = end_request(0, REQUEST_COMPLETE);
+                write_record(writer, END_REQUEST, request_id, &content).await?;
+                writer.flush().await?;
+                return Ok(None);
+            }
+            BEGIN_REQUEST => {
+                let content = end_request(0, CANT_MPX_CONN);
+                write_record(writer, END_REQUEST, record.request_id, &content).await?;
+                writer.flush().await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(FastCgiParams(decode_params(&data)?)))
+}
+
+/// Sends the stdin records of a request to its body, until their end
+async fn read_stdin<R>(
+    reader: &mut R,
+    request_id: u16,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let record = match read_record(reader).await {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                let msg = "FastCGI connection closed before the end of the request body";
+                let _ = sender
+                    .send(Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg)))
+                    .await;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+            }
+            Err(err) => {
+                let _ = sender
+                    .send(Err(io::Error::new(err.kind(), "FastCGI read failed")))
+                    .await;
+                return Err(err);
+            }
+        };
+        if record.request_id != request_id {
+            continue;
+        }
+        match record.kind {
+            STDIN if record.content.is_empty() => return Ok(()),
+            // The body may have been dropped, the records are read anyway
+            STDIN => {
+                let _ = sender.send(Ok(record.content)).await;
+            }
+            ABORT_REQUEST => {
+                let _ = sender
+                    .send(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "FastCGI request aborted",
+                    )))
+                    .await;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Request of the params of a FastCGI request
+fn fastcgi_request(
+    params: FastCgiParams,
+    body: FastCgiBody,
+) -> Result<Request<FastCgiBody>, String> {
+    let mut builder = Request::builder().method(params.get("REQUEST_METHOD").unwrap_or("GET"));
+    builder = match params.get("REQUEST_URI").filter(|uri| !uri.is_empty()) {
+        Some(uri) => builder.uri(uri),
+        None => {
+            let mut uri = params.get("SCRIPT_NAME").unwrap_or_default().to_string()
+                + params.get("PATH_INFO").unwrap_or_default();
+            if let Some(query) = params.get("QUERY_STRING").filter(|q| !q.is_empty()) {
+                uri = uri + "?" + query;
+            }
+            builder.uri(uri)
+        }
+    };
+    for (name, value) in &params.0 {
+        if let Some(name) = name.strip_prefix("HTTP_") {
+            builder = builder.header(name.replace('_', "-"), value);
+        }
+    }
+    for (param, header) in [
+        ("CONTENT_TYPE", hyper::header::CONTENT_TYPE),
+        ("CONTENT_LENGTH", hyper::header::CONTENT_LENGTH),
+    ] {
+        if let Some(value) = params.get(param).filter(|value| !value.is_empty()) {
+            builder = builder.header(header, value);
+        }
+    }
+    let remote = params
+        .get("REMOTE_ADDR")
+        .and_then(|addr| addr.parse().ok())
+        .map(|addr| {
+            SocketAddr::new(
+                addr,
+                params
+                    .get("REMOTE_PORT")
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or(0),
+            )
+        });
+    if let Some(remote) = remote {
+        builder = builder.extension(ConnectInfo(remote));
+    }
+    builder
+        .extension(params)
+        .body(body)
+        .map_err(|err| err.to_string())
+}
+
+/// Writes a response as the stdout of a request, and ends the request
+async fn write_response<W, B>(
+    writer: &mut W,
+    request_id: u16,
+    response: Response<B>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    B: Body,
+    B::Data: AsRef<[u8]>,
+    B::Error: Display,
+{
+    let status = response.status();
+    let mut head = format!(
+        "Status: {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("unknown reason")
+    )
+    .into_bytes();
+    for (k, v) in response.headers() {
+        head.extend_from_slice(k.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(v.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    write_stream(writer, STDOUT, request_id, &head).await?;
+    writer.flush().await?;
+
+    let mut app_status = 0;
+    let mut body = pin!(response.into_body());
+    while let Some(frame) = body.frame().await {
+        match frame {
+            Ok(frame) => {
+                if let Ok(data) = frame.into_data() {
+                    write_stream(writer, STDOUT, request_id, data.as_ref()).await?;
+                    writer.flush().await?;
+                }
+            }
+            Err(err) => {
+                debug!("FastCGI response body failed: {}", err);
+                app_status = 1;
+                break;
+            }
+        }
+    }
+    end_stdout(writer, request_id, app_status).await
+}
+
+/// Writes an empty response with a status, and ends the request
+async fn write_error<W>(writer: &mut W, request_id: u16, status: StatusCode) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let head = format!(
+        "Status: {} {}\r\n\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("unknown reason")
+    );
+    write_stream(writer, STDOUT, request_id, head.as_bytes()).await?;
+    end_stdout(writer, request_id, 1).await
+}
+
+async fn end_stdout<W>(writer: &mut W, request_id: u16, app_status: u32) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_record(writer, STDOUT, request_id, &[]).await?;
+    let content = end_request(app_status, REQUEST_COMPLETE);
+    write_record(writer, END_REQUEST, request_id, &content).await?;
+    writer.flush().await
+}
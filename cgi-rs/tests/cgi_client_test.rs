@@ -0,0 +1,400 @@
+use std::{collections::HashMap, convert::Infallible, time::Duration};
+
+use bytes::Bytes;
+use cgi_rs::{
+    client::{run_fastcgi, run_scgi, FastCgiBody, ScgiBody, MAX_FASTCGI_REQUEST_STDIN},
+    common::ConnInfo,
+};
+use futures::{stream, StreamExt};
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::{
+    body::{Body, Frame},
+    service::service_fn,
+    Request, Response,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+fn fastcgi_record(kind: u8, request_id: u16, content: &[u8]) -> Vec<u8> {
+    let mut record = vec![1, kind];
+    record.extend_from_slice(&request_id.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(content);
+    record
+}
+
+fn fastcgi_params(params: &[(&str, &str)]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encoded.extend_from_slice(&[name.len() as u8, value.len() as u8]);
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    encoded
+}
+
+async fn read_fastcgi_record<R: AsyncRead + Unpin>(reader: &mut R) -> (u8, u16, Vec<u8>) {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await.unwrap();
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0u8; len + header[6] as usize];
+    reader.read_exact(&mut content).await.unwrap();
+    content.truncate(len);
+    (
+        header[1],
+        u16::from_be_bytes([header[2], header[3]]),
+        content,
+    )
+}
+
+fn echo<B>(
+    conn_info: ConnInfo,
+) -> impl hyper::service::Service<
+    Request<B>,
+    Response = Response<Full<Bytes>>,
+    Error = Infallible,
+    Future = impl Send,
+>
+where
+    B: Body<Data = Bytes, Error = std::io::Error> + Send + 'static,
+{
+    service_fn(move |req: Request<B>| async move {
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let body = req.into_body().collect().await.unwrap().to_bytes();
+        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!(
+            "{} {} from {}:{} {}",
+            method,
+            path,
+            conn_info.remote_addr,
+            conn_info.remote_port,
+            String::from_utf8_lossy(&body)
+        )))))
+    })
+}
+
+fn request_params<'a>(method: &'a str, uri: &'a str, length: &'a str) -> Vec<(&'a str, &'a str)> {
+    vec![
+        ("REQUEST_METHOD", method),
+        ("SERVER_PROTOCOL", "HTTP/1.1"),
+        ("REQUEST_URI", uri),
+        ("CONTENT_LENGTH", length),
+        ("SERVER_NAME", "localhost"),
+        ("SERVER_PORT", "80"),
+        ("REMOTE_ADDR", "10.0.0.1"),
+        ("REMOTE_PORT", "5000"),
+    ]
+}
+
+#[tokio::test]
+async fn fastcgi_multiplexed_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(run_fastcgi(listener.into(), echo::<FastCgiBody>));
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let keep_conn = [0, 1, 1, 0, 0, 0, 0, 0];
+    let mut records = Vec::new();
+    records.extend(fastcgi_record(1, 1, &keep_conn));
+    records.extend(fastcgi_record(1, 2, &keep_conn));
+    let params = fastcgi_params(&request_params("POST", "/first", "5"));
+    records.extend(fastcgi_record(4, 1, &params));
+    let params = fastcgi_params(&request_params("GET", "/second", ""));
+    records.extend(fastcgi_record(4, 2, &params));
+    records.extend(fastcgi_record(4, 2, b""));
+    records.extend(fastcgi_record(5, 2, b""));
+    records.extend(fastcgi_record(4, 1, b""));
+    records.extend(fastcgi_record(5, 1, b"hello"));
+    records.extend(fastcgi_record(5, 1, b""));
+    stream.write_all(&records).await.unwrap();
+
+    let mut stdout: HashMap<u16, Vec<u8>> = HashMap::new();
+    let mut ended = 0;
+    while ended < 2 {
+        match read_fastcgi_record(&mut stream).await {
+            (6, id, content) => stdout.entry(id).or_default().extend_from_slice(&content),
+            (3, _, content) => {
+                assert_eq!(content[4], 0);
+                ended += 1;
+            }
+            (kind, _, _) => panic!("unexpected record {}", kind),
+        }
+    }
+    let first = String::from_utf8(stdout.remove(&1).unwrap()).unwrap();
+    assert!(first.starts_with("Status: 200 OK\r\n"), "{}", first);
+    assert!(
+        first.ends_with("\r\n\r\nPOST /first from 10.0.0.1:5000 hello"),
+        "{}",
+        first
+    );
+    let second = String::from_utf8(stdout.remove(&2).unwrap()).unwrap();
+    assert!(
+        second.ends_with("\r\n\r\nGET /second from 10.0.0.1:5000 "),
+        "{}",
+        second
+    );
+
+    // Invalid requests are answered without calling the service
+    let mut records = fastcgi_record(1, 3, &[0, 1, 0, 0, 0, 0, 0, 0]);
+    records.extend(fastcgi_record(4, 3, b""));
+    records.extend(fastcgi_record(5, 3, b""));
+    stream.write_all(&records).await.unwrap();
+    let (kind, _, content) = read_fastcgi_record(&mut stream).await;
+    assert_eq!(kind, 6);
+    assert!(content.starts_with(b"Status: 400"));
+}
+
+#[tokio::test]
+async fn fastcgi_unread_stdin_does_not_block_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    // The request of /held keeps its body without reading it
+    tokio::spawn(run_fastcgi(listener.into(), |_| {
+        service_fn(|req: Request<FastCgiBody>| async move {
+            let path = req.uri().path().to_string();
+            if path == "/held" {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!(
+                "{} {}",
+                path,
+                String::from_utf8_lossy(&body)
+            )))))
+        })
+    }));
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let keep_conn = [0, 1, 1, 0, 0, 0, 0, 0];
+    let mut records = Vec::new();
+    // More stdin records than buffered by a body, for a body without length
+    records.extend(fastcgi_record(1, 1, &keep_conn));
+    let params = fastcgi_params(&request_params("POST", "/held", "0"));
+    records.extend(fastcgi_record(4, 1, &params));
+    records.extend(fastcgi_record(4, 1, b""));
+    for _ in 0..64 {
+        records.extend(fastcgi_record(5, 1, b"ignored"));
+    }
+    records.extend(fastcgi_record(5, 1, b""));
+    records.extend(fastcgi_record(1, 2, &keep_conn));
+    let params = fastcgi_params(&request_params("POST", "/second", "5"));
+    records.extend(fastcgi_record(4, 2, &params));
+    records.extend(fastcgi_record(4, 2, b""));
+    records.extend(fastcgi_record(5, 2, b"hello"));
+    records.extend(fastcgi_record(5, 2, b""));
+    stream.write_all(&records).await.unwrap();
+
+    // The second request is answered while the first one is held
+    let mut stdout = Vec::new();
+    let read = async {
+        loop {
+            match read_fastcgi_record(&mut stream).await {
+                (6, 2, content) => stdout.extend_from_slice(&content),
+                (3, 2, _) => break,
+                (kind, id, _) => panic!("unexpected record {} of {}", kind, id),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(1), read)
+        .await
+        .unwrap();
+    let second = String::from_utf8(stdout).unwrap();
+    assert!(second.ends_with("\r\n\r\n/second hello"), "{}", second);
+}
+
+/// Signals the drop of a response body
+struct Released(mpsc::UnboundedSender<()>);
+
+impl Drop for Released {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+#[tokio::test]
+async fn fastcgi_stdin_buffer_limit() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    // The request of /held keeps its body without reading it
+    tokio::spawn(run_fastcgi(listener.into(), |_| {
+        service_fn(|req: Request<FastCgiBody>| async move {
+            if req.uri().path() == "/held" {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            Ok::<_, Infallible>(Response::new(Full::new(body)))
+        })
+    }));
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let keep_conn = [0, 1, 1, 0, 0, 0, 0, 0];
+    let chunk = vec![b'x'; 60000];
+    // Beyond the records already taken by the body
+    let count = 3 * MAX_FASTCGI_REQUEST_STDIN / chunk.len();
+    let length = (count * chunk.len()).to_string();
+    let mut records = fastcgi_record(1, 1, &keep_conn);
+    let params = fastcgi_params(&request_params("POST", "/held", &length));
+    records.extend(fastcgi_record(4, 1, &params));
+    records.extend(fastcgi_record(4, 1, b""));
+    for _ in 0..count {
+        records.extend(fastcgi_record(5, 1, &chunk));
+    }
+    stream.write_all(&records).await.unwrap();
+
+    // The request is ended once its buffer is full, the connection is kept
+    let read = async {
+        loop {
+            match read_fastcgi_record(&mut stream).await {
+                (6, 1, _) => {}
+                (3, 1, _) => break,
+                (kind, id, _) => panic!("unexpected record {} of {}", kind, id),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(1), read)
+        .await
+        .unwrap();
+
+    let mut records = fastcgi_record(1, 2, &keep_conn);
+    let params = fastcgi_params(&request_params("POST", "/second", "5"));
+    records.extend(fastcgi_record(4, 2, &params));
+    records.extend(fastcgi_record(4, 2, b""));
+    records.extend(fastcgi_record(5, 2, b"hello"));
+    records.extend(fastcgi_record(5, 2, b""));
+    stream.write_all(&records).await.unwrap();
+    let mut stdout = Vec::new();
+    loop {
+        match read_fastcgi_record(&mut stream).await {
+            (6, 2, content) => stdout.extend_from_slice(&content),
+            (3, 2, _) => break,
+            (kind, id, _) => panic!("unexpected record {} of {}", kind, id),
+        }
+    }
+    let second = String::from_utf8(stdout).unwrap();
+    assert!(second.ends_with("\r\n\r\nhello"), "{}", second);
+}
+
+#[tokio::test]
+async fn fastcgi_closed_connection_releases_response() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (released, mut on_release) = mpsc::unbounded_channel();
+    // Endless response, larger than what is buffered for the connection
+    tokio::spawn(run_fastcgi(listener.into(), move |_| {
+        let released = released.clone();
+        service_fn(move |_: Request<FastCgiBody>| {
+            let guard = Released(released.clone());
+            async move {
+                let chunks = stream::repeat(()).map(move |_| {
+                    let _guard = &guard;
+                    Ok::<_, Infallible>(Frame::data(Bytes::from(vec![b'x'; 4096])))
+                });
+                Ok::<_, Infallible>(Response::new(StreamBody::new(chunks)))
+            }
+        })
+    }));
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut records = fastcgi_record(1, 1, &[0, 1, 0, 0, 0, 0, 0, 0]);
+    let params = fastcgi_params(&request_params("GET", "/large", ""));
+    records.extend(fastcgi_record(4, 1, &params));
+    records.extend(fastcgi_record(4, 1, b""));
+    records.extend(fastcgi_record(5, 1, b""));
+    stream.write_all(&records).await.unwrap();
+    let (kind, _, _) = read_fastcgi_record(&mut stream).await;
+    assert_eq!(kind, 6);
+    drop(stream);
+
+    // The body is dropped once writing to the connection fails
+    tokio::time::timeout(Duration::from_secs(5), on_release.recv())
+        .await
+        .expect("response body still held")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn fastcgi_abort_request() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (released, mut on_release) = mpsc::unbounded_channel();
+    // The service only answers after the test
+    tokio::spawn(run_fastcgi(listener.into(), move |_| {
+        let released = released.clone();
+        service_fn(move |_: Request<FastCgiBody>| {
+            let guard = Released(released.clone());
+            async move {
+                let _guard = guard;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::new())))
+            }
+        })
+    }));
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let keep_conn = [0, 1, 1, 0, 0, 0, 0, 0];
+    let mut records = fastcgi_record(1, 1, &keep_conn);
+    let params = fastcgi_params(&request_params("GET", "/slow", ""));
+    records.extend(fastcgi_record(4, 1, &params));
+    records.extend(fastcgi_record(4, 1, b""));
+    records.extend(fastcgi_record(5, 1, b""));
+    // A request aborted before its params gets no stdout
+    records.extend(fastcgi_record(1, 2, &keep_conn));
+    records.extend(fastcgi_record(2, 2, b""));
+    stream.write_all(&records).await.unwrap();
+    let (kind, id, _) = read_fastcgi_record(&mut stream).await;
+    assert_eq!((kind, id), (3, 2));
+
+    stream.write_all(&fastcgi_record(2, 1, b"")).await.unwrap();
+    let read = async {
+        loop {
+            match read_fastcgi_record(&mut stream).await {
+                (6, 1, _) => {}
+                (3, 1, content) => break content,
+                (kind, id, _) => panic!("unexpected record {} of {}", kind, id),
+            }
+        }
+    };
+    let content = tokio::time::timeout(Duration::from_secs(1), read)
+        .await
+        .unwrap();
+    assert_eq!(content[4], 0);
+    tokio::time::timeout(Duration::from_secs(1), on_release.recv())
+        .await
+        .expect("service not cancelled")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn scgi_request() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(run_scgi(listener.into(), echo::<ScgiBody>));
+
+    let mut header = Vec::new();
+    for (name, value) in request_params("POST", "/app", "5") {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(value.as_bytes());
+        header.push(0);
+    }
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(format!("{}:", header.len()).as_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&header).await.unwrap();
+    stream.write_all(b",hello").await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("Status: 200 OK\r\n"), "{}", response);
+    assert!(
+        response.ends_with("\r\n\r\nPOST /app from 10.0.0.1:5000 hello"),
+        "{}",
+        response
+    );
+}
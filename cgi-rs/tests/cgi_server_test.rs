@@ -1,4 +1,1373 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use cgi_rs::server::{
+    serve_fastcgi_connection, BackendAddress, CgiServerError, ConnectInfo, ErrorHandler,
+    FastCgiBackend, FastCgiPool, FastCgiWrap, Interpreter, LocalRedirect, NphMode, ParseConfig,
+    ParseMode, ProcessInfo, ResourceLimits, RingBufferSink, RunAs, Sandbox, ScgiBackend, Script,
+    ScriptAlias, ScriptRouter, SpoolConfig,
+};
+use futures::{stream, StreamExt};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Full, StreamBody};
+use hyper::{
+    body::Frame,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    Request, StatusCode,
+};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
 #[test]
 fn test_add() {
     assert_eq!(5, 5);
 }
+
+fn write_script(dir: &TempDir, name: &str, content: &str) -> PathBuf {
+    let path = dir.path().join(name);
+    let mut file = std::fs::File::create(&path).unwrap();
+    file.write_all(content.as_bytes()).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+fn script(path: PathBuf) -> Script {
+    Script {
+        path,
+        root: PathBuf::new(),
+        dir: None,
+        env: Vec::new(),
+        args: Vec::new(),
+        inherited_env: Vec::new(),
+        spool: None,
+        nph: NphMode::Auto,
+        local_redirect: None,
+        parse_config: ParseConfig::default(),
+        error_handler: None,
+        stderr_sink: None,
+        interpreters: HashMap::new(),
+        rlimits: None,
+        run_as: None,
+        sandbox: None,
+        kill_grace: None,
+        header_timeout: None,
+        request_timeout: None,
+    }
+}
+
+type Frames = Vec<Result<Frame<Bytes>, std::io::Error>>;
+
+fn chunked_request(
+    chunks: &[&'static str],
+) -> Request<StreamBody<stream::Iter<<Frames as IntoIterator>::IntoIter>>> {
+    let frames: Frames = chunks
+        .iter()
+        .map(|c| Ok(Frame::data(Bytes::from_static(c.as_bytes()))))
+        .collect();
+    Request::builder()
+        .method("POST")
+        .uri("/")
+        .header(TRANSFER_ENCODING, "chunked")
+        .body(StreamBody::new(stream::iter(frames)))
+        .unwrap()
+}
+
+fn remote() -> SocketAddr {
+    "127.0.0.1:4000".parse().unwrap()
+}
+
+const ECHO_SCRIPT: &str = "#!/bin/sh
+echo 'Content-Type: text/plain'
+echo
+echo \"$CONTENT_LENGTH\"
+cat
+";
+
+#[tokio::test]
+async fn chunked_request_is_rejected_without_spool() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(&dir, "echo.sh", ECHO_SCRIPT));
+    let res = script
+        .serve(chunked_request(&["hello"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn chunked_request_is_spooled() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut script = script(write_script(&dir, "echo.sh", ECHO_SCRIPT));
+    script.spool = Some(SpoolConfig {
+        memory_threshold: 4,
+        ..SpoolConfig::default()
+    });
+    let res = script
+        .serve(chunked_request(&["hello", " world"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "11\nhello world");
+}
+
+#[tokio::test]
+async fn spooled_request_too_large() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut script = script(write_script(&dir, "echo.sh", ECHO_SCRIPT));
+    script.spool = Some(SpoolConfig {
+        max_size: 8,
+        ..SpoolConfig::default()
+    });
+    let res = script
+        .serve(chunked_request(&["hello", " world"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+const SEEK_SCRIPT: &str = "#!/bin/sh
+echo 'Content-Type: text/plain'
+echo
+if [ -f /dev/stdin ]; then echo file; else echo pipe; fi
+head -c 5 > /dev/null
+cat /dev/stdin
+";
+
+#[tokio::test]
+async fn spooled_file_is_seekable_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut script = script(write_script(&dir, "seek.sh", SEEK_SCRIPT));
+    script.spool = Some(SpoolConfig {
+        memory_threshold: 4,
+        ..SpoolConfig::default()
+    });
+    let res = script
+        .serve(chunked_request(&["hello", " world"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    // Reopening the file reads it again from its beginning
+    assert_eq!(body, "file\nhello world");
+
+    script.spool = Some(SpoolConfig::default());
+    let res = script
+        .serve(chunked_request(&["hello", " world"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert!(body.starts_with(b"pipe\n"));
+}
+
+const NPH_SCRIPT: &str = "#!/bin/sh
+printf 'HTTP/1.1 299 Custom\\r\\n'
+printf 'X-Custom: value\\r\\n'
+printf '\\r\\n'
+printf 'nph body'
+";
+
+#[tokio::test]
+async fn nph_script_is_relayed() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(&dir, "nph-test.sh", NPH_SCRIPT));
+    assert!(script.is_nph());
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status().as_u16(), 299);
+    assert_eq!(res.headers()["x-custom"], "value");
+    assert!(res.headers().get(CONTENT_TYPE).is_none());
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "nph body");
+}
+
+const REDIRECT_SCRIPT: &str = "#!/bin/sh
+if [ \"$PATH_INFO\" = /target ]; then
+    echo 'Content-Type: text/plain'
+    echo
+    echo \"$REDIRECT_URL $REDIRECT_QUERY_STRING $REDIRECT_STATUS $QUERY_STRING\"
+else
+    echo 'Location: /target?b=2'
+    echo
+fi
+";
+
+#[tokio::test]
+async fn local_redirect_same_script() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut script = script(write_script(&dir, "redirect.sh", REDIRECT_SCRIPT));
+    script.local_redirect = Some(LocalRedirect::SameScript);
+    let req = Request::builder()
+        .uri("/source?a=1")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "/source a=1 200 b=2\n");
+}
+
+#[tokio::test]
+async fn local_redirect_loop() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut script = script(write_script(
+        &dir,
+        "loop.sh",
+        "#!/bin/sh\necho 'Location: /again'\necho\n",
+    ));
+    script.local_redirect = Some(LocalRedirect::SameScript);
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn local_redirect_disabled() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(&dir, "redirect.sh", REDIRECT_SCRIPT));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(res.headers()["location"], "/target?b=2");
+}
+
+#[tokio::test]
+async fn local_location_with_body_is_client_redirect() {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, content) in [
+        (
+            "body.sh",
+            "#!/bin/sh\necho 'Location: /target'\necho\necho 'moved'\n",
+        ),
+        (
+            "header.sh",
+            "#!/bin/sh\necho 'Location: /target'\necho 'Content-Type: text/plain'\necho\n",
+        ),
+    ] {
+        let mut script = script(write_script(&dir, name, content));
+        script.local_redirect = Some(LocalRedirect::SameScript);
+        let req = Request::builder()
+            .uri("/")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()["location"], "/target");
+    }
+}
+
+const BAD_HEADER_SCRIPT: &str = "#!/bin/sh
+echo 'content-type: text/plain'
+echo 'not a header'
+echo
+echo 'body'
+";
+
+#[tokio::test]
+async fn bad_header_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut script = script(write_script(&dir, "bad.sh", BAD_HEADER_SCRIPT));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+
+    script.parse_config.mode = ParseMode::Strict;
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn script_fails_before_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(&dir, "fail.sh", "#!/bin/sh\nexit 3\n"));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let info = res.extensions().get::<ProcessInfo>().unwrap();
+    assert!(info.pid().is_some());
+    assert_eq!(info.exit_status().unwrap().code(), Some(3));
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&body).contains("exit status: 3"));
+}
+
+#[tokio::test]
+async fn script_closes_stdout_before_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(
+        &dir,
+        "close.sh",
+        "#!/bin/sh\nexec >&-\nsleep 30\n",
+    ));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        script.serve(req, remote(), Vec::new()),
+    )
+    .await
+    .expect("the script is not waited for")
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    // The script is terminated with the request
+    let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+    assert!(info.wait().await.is_some());
+}
+
+#[tokio::test]
+async fn script_fails_after_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(
+        &dir,
+        "fail.sh",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho partial\nexit 1\n",
+    ));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+    assert!(res.into_body().collect().await.is_err());
+    assert_eq!(info.wait().await.unwrap().code(), Some(1));
+}
+
+#[tokio::test]
+async fn error_handler() {
+    let dir = tempfile::tempdir().unwrap();
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let recorded = errors.clone();
+    let handler = ErrorHandler::new(move |err| {
+        recorded.lock().unwrap().push(err.to_string());
+        match err {
+            CgiServerError::Spawn { .. } => Some(
+                hyper::Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Empty::new().map_err(|never| match never {}).boxed())
+                    .unwrap(),
+            ),
+            _ => None,
+        }
+    });
+
+    let mut missing = script(dir.path().join("missing.sh"));
+    missing.error_handler = Some(handler.clone());
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = missing.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let mut failing = script(write_script(
+        &dir,
+        "fail.sh",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\nexit 1\n",
+    ));
+    failing.error_handler = Some(handler);
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = failing.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let err = res.into_body().collect().await.unwrap_err();
+    assert!(matches!(
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<CgiServerError>()),
+        Some(CgiServerError::ExitedAfterHeader(_))
+    ));
+
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].starts_with("Cannot run cgi executable"));
+    assert!(errors[1].contains("exit status: 1"));
+}
+
+#[tokio::test]
+async fn stderr_sink() {
+    let dir = tempfile::tempdir().unwrap();
+    let sink = RingBufferSink::new(16);
+    let mut script = script(write_script(
+        &dir,
+        "stderr.sh",
+        "#!/bin/sh\necho 'first error' >&2\necho 'second error' >&2\necho 'Content-Type: text/plain'\necho\n",
+    ));
+    script.stderr_sink = Some(Arc::new(sink.clone()));
+    let req = Request::builder()
+        .uri("/")
+        .header("x-request-id", "req-42")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+    let pid = info.pid();
+    res.into_body().collect().await.unwrap();
+    info.wait().await.unwrap();
+
+    let lines = sink.lines_of("req-42");
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].line, b"first error");
+    assert_eq!(lines[1].line, b"second error");
+    assert_eq!(lines[0].meta.pid, pid);
+    assert_eq!(lines[0].meta.remote, remote());
+    assert_eq!(lines[0].meta.script, script.path);
+}
+
+#[tokio::test]
+async fn script_service() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(
+        &dir,
+        "remote.sh",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho \"$REMOTE_ADDR:$REMOTE_PORT\"\n",
+    ));
+    let mut service = script.service_with_writer(Vec::new());
+
+    let req = Request::builder()
+        .uri("/")
+        .extension(ConnectInfo(remote()))
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    futures::future::poll_fn(|cx| {
+        tower_service::Service::<Request<Empty<Bytes>>>::poll_ready(&mut service, cx)
+    })
+    .await
+    .unwrap();
+    let res = tower_service::Service::call(&mut service, req)
+        .await
+        .unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, format!("{}\n", remote()));
+
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = hyper::service::Service::call(&service, req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "0.0.0.0:0\n");
+}
+
+#[tokio::test]
+async fn script_service_with_unsync_body() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = script(write_script(&dir, "echo.sh", ECHO_SCRIPT));
+    let mut service = script.service_with_writer(Vec::new());
+
+    // Body of axum requests, which is not Sync
+    let body: UnsyncBoxBody<Bytes, std::convert::Infallible> =
+        Full::new(Bytes::from("hello")).boxed_unsync();
+    let req = Request::builder()
+        .method("POST")
+        .uri("/")
+        .header(CONTENT_LENGTH, 5)
+        .body(body)
+        .unwrap();
+    let res = tower_service::Service::call(&mut service, req)
+        .await
+        .unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "5\nhello");
+}
+
+#[tokio::test]
+async fn router() {
+    let dir = tempfile::tempdir().unwrap();
+    let env_script = "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho \"$SCRIPT_NAME|$PATH_INFO|$USER_ID\"\n";
+    let router = ScriptRouter::new()
+        .prefix("/app", script(write_script(&dir, "app.sh", env_script)))
+        .regex(
+            regex::Regex::new(r"^/users/(?P<USER_ID>\d+)").unwrap(),
+            script(write_script(&dir, "users.sh", env_script)),
+        );
+
+    for (uri, expected) in [
+        ("/app/a/b?x=1", "/app|/a/b|\n"),
+        ("/users/42/edit", "/users/42|/edit|42\n"),
+    ] {
+        let req = Request::builder()
+            .uri(uri)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let res = router.serve(req, remote(), Vec::new()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, expected);
+    }
+
+    let req = Request::builder()
+        .uri("/other")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = router.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let router = router.fallback(script(write_script(&dir, "fallback.sh", env_script)));
+    let req = Request::builder()
+        .uri("/other")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = router.serve(req, remote(), Vec::new()).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "/|/other|\n");
+}
+
+#[tokio::test]
+async fn script_alias() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_script(
+        &dir,
+        "env.cgi",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho \"$SCRIPT_NAME|$SCRIPT_FILENAME|$PATH_INFO\"\n",
+    );
+    std::fs::write(dir.path().join("data.txt"), "data").unwrap();
+    let alias = ScriptAlias {
+        prefix: "/cgi-bin".to_string(),
+        dir: dir.path().to_path_buf(),
+        script: script(PathBuf::new()),
+    };
+
+    let req = Request::builder()
+        .uri("/cgi-bin/env.cgi/extra")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = alias.serve(req, remote(), Vec::new()).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(
+        body,
+        format!(
+            "/cgi-bin/env.cgi|{}|/extra\n",
+            path.canonicalize().unwrap().display()
+        )
+    );
+
+    let req = Request::builder()
+        .uri("/cgi-bin/data.txt")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = alias.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn interpreter() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("env.sh");
+    std::fs::write(
+        &path,
+        "echo 'Content-Type: text/plain'\necho\necho \"$0|$SCRIPT_FILENAME|$INTERPRETER_VAR\"\n",
+    )
+    .unwrap();
+    let mut script = script(path.clone());
+    script.interpreters.insert(
+        "sh".to_string(),
+        Interpreter {
+            path: PathBuf::from("/bin/sh"),
+            args: Vec::new(),
+            env: vec![("INTERPRETER_VAR".to_string(), "set".to_string())],
+        },
+    );
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = script.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let path = path.display();
+    assert_eq!(body, format!("{}|{}|set\n", path, path));
+}
+
+async fn read_fastcgi_record<R: AsyncRead + Unpin>(reader: &mut R) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await.unwrap();
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0u8; len + header[6] as usize];
+    reader.read_exact(&mut content).await.unwrap();
+    content.truncate(len);
+    (header[1], content)
+}
+
+fn fastcgi_record(kind: u8, content: &[u8]) -> Vec<u8> {
+    let mut record = vec![1, kind, 0, 1];
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(content);
+    record
+}
+
+fn decode_fastcgi_length(data: &mut &[u8]) -> usize {
+    if data[0] < 0x80 {
+        let len = data[0] as usize;
+        *data = &data[1..];
+        len
+    } else {
+        let len = u32::from_be_bytes([data[0] & 0x7f, data[1], data[2], data[3]]) as usize;
+        *data = &data[4..];
+        len
+    }
+}
+
+/// Responder answering a single request with some of its params and its stdin
+async fn fastcgi_responder(listener: TcpListener) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    fastcgi_respond(&mut stream).await;
+}
+
+/// Answers a request read from the stream, returns its params and whether
+/// the connection is kept open
+async fn fastcgi_respond(stream: &mut TcpStream) -> (HashMap<String, String>, bool) {
+    let (kind, begin) = read_fastcgi_record(stream).await;
+    assert_eq!(kind, 1);
+    let keep_conn = begin[2] & 1 == 1;
+    let mut params = Vec::new();
+    let mut stdin = Vec::new();
+    loop {
+        match read_fastcgi_record(stream).await {
+            (4, content) => params.extend_from_slice(&content),
+            (5, content) if content.is_empty() => break,
+            (5, content) => stdin.extend_from_slice(&content),
+            (kind, _) => panic!("unexpected record {}", kind),
+        }
+    }
+    let mut data = params.as_slice();
+    let mut env = HashMap::new();
+    while !data.is_empty() {
+        let name_len = decode_fastcgi_length(&mut data);
+        let value_len = decode_fastcgi_length(&mut data);
+        let name = String::from_utf8(data[..name_len].to_vec()).unwrap();
+        let value = String::from_utf8(data[name_len..name_len + value_len].to_vec()).unwrap();
+        env.insert(name, value);
+        data = &data[name_len + value_len..];
+    }
+
+    let body = format!(
+        "Content-Type: text/plain\r\n\r\n{}|{}|{}|{}",
+        env["SCRIPT_FILENAME"],
+        env["SCRIPT_NAME"],
+        env["PATH_INFO"],
+        String::from_utf8(stdin).unwrap()
+    );
+    stream
+        .write_all(&fastcgi_record(7, b"warning\n"))
+        .await
+        .unwrap();
+    stream
+        .write_all(&fastcgi_record(6, body.as_bytes()))
+        .await
+        .unwrap();
+    stream.write_all(&fastcgi_record(6, b"")).await.unwrap();
+    stream
+        .write_all(&fastcgi_record(3, &[0, 0, 0, 0, 0, 0, 0, 0]))
+        .await
+        .unwrap();
+    (env, keep_conn)
+}
+
+#[tokio::test]
+async fn fastcgi_backend() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let responder = tokio::spawn(fastcgi_responder(listener));
+    let mut script = script(PathBuf::from("/srv/app.py"));
+    script.root = PathBuf::from("/app");
+    let sink = RingBufferSink::new(8);
+    script.stderr_sink = Some(Arc::new(sink.clone()));
+    let backend = FastCgiBackend {
+        address: address.parse().unwrap(),
+        script,
+        pool: FastCgiPool::default(),
+    };
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/app/info")
+        .header("Content-Length", "5")
+        .body(Full::new(Bytes::from_static(b"hello")))
+        .unwrap();
+    let res = backend.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "/srv/app.py|/app|/info|hello");
+    responder.await.unwrap();
+    // stderr is forwarded by its own task
+    for _ in 0..100 {
+        if !sink.lines().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let lines = sink.lines();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].line, b"warning");
+}
+
+#[tokio::test]
+async fn fastcgi_backend_keeps_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    // A single connection is accepted
+    let responder = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            requests.push(fastcgi_respond(&mut stream).await);
+        }
+        requests
+    });
+    let backend = FastCgiBackend {
+        address: address.parse().unwrap(),
+        script: script(PathBuf::from("/srv/app.py")),
+        pool: FastCgiPool::new(4),
+    };
+
+    for body in ["first", "second"] {
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("/{}", body))
+            .header("Content-Length", body.len())
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+        let res = backend.serve(req, remote(), Vec::new()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res_body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(res_body, format!("/srv/app.py|/|/{}|{}", body, body));
+        // The connection is given back once the request has ended
+        for _ in 0..100 {
+            if backend.pool.idle() == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(backend.pool.idle(), 1);
+    }
+
+    let requests = tokio::time::timeout(std::time::Duration::from_secs(5), responder)
+        .await
+        .unwrap()
+        .unwrap();
+    for (env, keep_conn) in requests {
+        assert!(keep_conn);
+        // Only meta-variables are sent to the application
+        assert!(!env.contains_key("PATH"));
+        assert_eq!(env["GATEWAY_INTERFACE"], "CGI/1.1");
+    }
+}
+
+#[tokio::test]
+async fn fastcgi_backend_unavailable() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let backend = FastCgiBackend {
+        address: BackendAddress::Tcp(address),
+        script: script(PathBuf::from("/srv/app.py")),
+        pool: FastCgiPool::default(),
+    };
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = backend.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+}
+
+/// SCGI application answering a single request with some of its headers
+/// and its body
+async fn scgi_app(listener: TcpListener) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+    let mut len = Vec::new();
+    tokio::io::AsyncBufReadExt::read_until(&mut stream, b':', &mut len)
+        .await
+        .unwrap();
+    let len: usize = std::str::from_utf8(&len[..len.len() - 1])
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut header = vec![0u8; len + 1];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header.pop(), Some(b','));
+    let fields: Vec<&str> = std::str::from_utf8(&header)
+        .unwrap()
+        .trim_end_matches('\0')
+        .split('\0')
+        .collect();
+    assert_eq!(fields[..4], ["CONTENT_LENGTH", "5", "SCGI", "1"]);
+    let env: HashMap<&str, &str> = fields.chunks(2).map(|field| (field[0], field[1])).collect();
+    let mut body = vec![0u8; env["CONTENT_LENGTH"].parse().unwrap()];
+    stream.read_exact(&mut body).await.unwrap();
+
+    let response = format!(
+        "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{}|{}|{}",
+        env["SCRIPT_FILENAME"],
+        env["REQUEST_METHOD"],
+        String::from_utf8(body).unwrap()
+    );
+    stream.write_all(response.as_bytes()).await.unwrap();
+}
+
+#[tokio::test]
+async fn scgi_backend() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let app = tokio::spawn(scgi_app(listener));
+    let backend = ScgiBackend {
+        address: address.parse().unwrap(),
+        script: script(PathBuf::from("/srv/app.py")),
+    };
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/")
+        .header("Content-Length", "5")
+        .body(Full::new(Bytes::from_static(b"hello")))
+        .unwrap();
+    let res = backend.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "/srv/app.py|POST|hello");
+    app.await.unwrap();
+}
+
+#[tokio::test]
+async fn scgi_backend_without_length() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let mut backend = ScgiBackend {
+        address: address.parse().unwrap(),
+        script: script(PathBuf::from("/srv/app.py")),
+    };
+
+    // The length cannot be sent before the body without spooling it
+    let res = backend
+        .serve(chunked_request(&["hel", "lo"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::LENGTH_REQUIRED);
+
+    let app = tokio::spawn(scgi_app(listener));
+    backend.script.spool = Some(SpoolConfig::default());
+    let res = backend
+        .serve(chunked_request(&["hel", "lo"]), remote(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "/srv/app.py|POST|hello");
+    app.await.unwrap();
+}
+
+#[tokio::test]
+async fn scgi_backend_sends_content_length_bytes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let app = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b",hello") {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"Content-Type: text/plain\r\n\r\nok")
+            .await
+            .unwrap();
+        // Nothing is sent after CONTENT_LENGTH bytes
+        let mut buf = [0u8; 1024];
+        let extra =
+            tokio::time::timeout(std::time::Duration::from_millis(200), stream.read(&mut buf))
+                .await;
+        assert!(matches!(extra, Err(_) | Ok(Ok(0))));
+    });
+    let backend = ScgiBackend {
+        address: address.parse().unwrap(),
+        script: script(PathBuf::from("/srv/app.py")),
+    };
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/")
+        .header("Content-Length", "5")
+        .body(Full::new(Bytes::from_static(b"hello world")))
+        .unwrap();
+    let res = backend.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    app.await.unwrap();
+}
+
+/// Output of a FastCGI request sent to a wrapped script
+async fn fastcgi_wrap_request(wrap: FastCgiWrap, params: &[(&str, &str)], stdin: &[u8]) -> String {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let connection = tokio::spawn(serve_fastcgi_connection(
+        server,
+        wrap.service_with_writer(Vec::new()),
+    ));
+
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encoded.extend_from_slice(&[name.len() as u8, value.len() as u8]);
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    client
+        .write_all(&fastcgi_record(1, &[0, 1, 0, 0, 0, 0, 0, 0]))
+        .await
+        .unwrap();
+    client
+        .write_all(&fastcgi_record(4, &encoded))
+        .await
+        .unwrap();
+    client.write_all(&fastcgi_record(4, b"")).await.unwrap();
+    client.write_all(&fastcgi_record(5, stdin)).await.unwrap();
+    client.write_all(&fastcgi_record(5, b"")).await.unwrap();
+
+    let mut stdout = Vec::new();
+    loop {
+        match read_fastcgi_record(&mut client).await {
+            (6, content) => stdout.extend_from_slice(&content),
+            (3, content) => {
+                assert_eq!(content[4], 0);
+                break;
+            }
+            (kind, _) => panic!("unexpected record {}", kind),
+        }
+    }
+    connection.await.unwrap().unwrap();
+    String::from_utf8(stdout).unwrap()
+}
+
+#[tokio::test]
+async fn fastcgi_wrap() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_script(
+        &dir,
+        "env.cgi",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho \"$SCRIPT_NAME|$PATH_INFO|$DOCUMENT_ROOT|$REMOTE_ADDR\"\ncat\n",
+    );
+    let filename = path.to_str().unwrap();
+    let wrap = FastCgiWrap {
+        script: script(PathBuf::new()),
+        allowed_root: dir.path().to_path_buf(),
+    };
+
+    let stdout = fastcgi_wrap_request(
+        wrap.clone(),
+        &[
+            ("SCRIPT_FILENAME", filename),
+            ("SCRIPT_NAME", "/env.cgi"),
+            ("PATH_INFO", "/extra"),
+            ("DOCUMENT_ROOT", "/srv/www"),
+            ("REQUEST_METHOD", "POST"),
+            ("REQUEST_URI", "/env.cgi/extra"),
+            ("CONTENT_LENGTH", "5"),
+            ("REMOTE_ADDR", "10.0.0.1"),
+            ("REMOTE_PORT", "5000"),
+        ],
+        b"hello",
+    )
+    .await;
+    assert!(stdout.starts_with("Status: 200 OK\r\n"), "{}", stdout);
+    assert!(
+        stdout.contains("content-type: text/plain\r\n"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.ends_with("\r\n\r\n/env.cgi|/extra|/srv/www|10.0.0.1\nhello"),
+        "{}",
+        stdout
+    );
+
+    let stdout = fastcgi_wrap_request(
+        wrap,
+        &[
+            ("SCRIPT_FILENAME", "/nonexistent/missing.cgi"),
+            ("REQUEST_METHOD", "GET"),
+            ("REQUEST_URI", "/missing.cgi"),
+        ],
+        b"",
+    )
+    .await;
+    assert!(
+        stdout.starts_with("Status: 404 Not Found\r\n"),
+        "{}",
+        stdout
+    );
+}
+
+#[tokio::test]
+async fn fastcgi_wrap_forwards_meta_variables() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_script(
+        &dir,
+        "env.cgi",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho \"${MY_SECRET-unset}|$PATH|$HTTP_X_TOKEN|$SERVER_ADDR\"\n",
+    );
+    let wrap = FastCgiWrap {
+        script: script(PathBuf::new()),
+        allowed_root: dir.path().to_path_buf(),
+    };
+
+    let stdout = fastcgi_wrap_request(
+        wrap,
+        &[
+            ("SCRIPT_FILENAME", path.to_str().unwrap()),
+            ("REQUEST_METHOD", "GET"),
+            ("REQUEST_URI", "/env.cgi"),
+            ("SERVER_ADDR", "10.0.0.2"),
+            ("HTTP_X_TOKEN", "abc"),
+            ("MY_SECRET", "leaked"),
+            ("PATH", "/evil"),
+        ],
+        b"",
+    )
+    .await;
+    assert!(stdout.starts_with("Status: 200 OK\r\n"), "{}", stdout);
+    let line = stdout.rsplit("\r\n\r\n").next().unwrap();
+    let fields: Vec<&str> = line.trim_end().split('|').collect();
+    assert_eq!(fields[0], "unset");
+    assert_ne!(fields[1], "/evil");
+    assert_eq!(fields[2..], ["abc", "10.0.0.2"]);
+}
+
+#[tokio::test]
+async fn fastcgi_wrap_allowed_root() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("cgi-bin")).unwrap();
+    let inside = write_script(&dir, "cgi-bin/ok.cgi", ECHO_SCRIPT);
+    let outside = write_script(&dir, "other.cgi", ECHO_SCRIPT);
+    let wrap = FastCgiWrap {
+        script: script(PathBuf::new()),
+        allowed_root: dir.path().join("cgi-bin"),
+    };
+
+    let escaped = dir.path().join("cgi-bin/../other.cgi");
+    for (filename, status) in [
+        (inside.to_str().unwrap(), "200 OK"),
+        (outside.to_str().unwrap(), "403 Forbidden"),
+        (escaped.to_str().unwrap(), "403 Forbidden"),
+    ] {
+        let stdout = fastcgi_wrap_request(
+            wrap.clone(),
+            &[
+                ("SCRIPT_FILENAME", filename),
+                ("REQUEST_METHOD", "GET"),
+                ("REQUEST_URI", "/"),
+            ],
+            b"",
+        )
+        .await;
+        assert!(
+            stdout.starts_with(&format!("Status: {}\r\n", status)),
+            "{}: {}",
+            filename,
+            stdout
+        );
+    }
+}
+
+#[tokio::test]
+async fn resource_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut niced = script(write_script(
+        &dir,
+        "limits.sh",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\nnice\n",
+    ));
+    niced.rlimits = Some(ResourceLimits {
+        nice: Some(5),
+        ..ResourceLimits::default()
+    });
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = niced.serve(req, remote(), Vec::new()).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "5\n");
+
+    let mut limited = script(write_script(
+        &dir,
+        "fsize.sh",
+        "#!/bin/sh\nexec head -c 100000 /dev/zero > \"$(dirname \"$0\")/out\"\n",
+    ));
+    limited.rlimits = Some(ResourceLimits {
+        file_size: Some(1000),
+        ..ResourceLimits::default()
+    });
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = limited.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("file size limit exceeded"), "{}", body);
+}
+
+#[tokio::test]
+async fn run_as() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = write_script(
+        &dir,
+        "id.sh",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\nid -u\n",
+    );
+    let mut suexec = script(path.clone());
+    suexec.run_as = Some(RunAs {
+        uid: 65534,
+        gid: 65534,
+        groups: Vec::new(),
+        allowed_dirs: vec![dir.path().to_path_buf()],
+    });
+
+    // Owned by the server user
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = suexec.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    // The reason is only logged
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "Forbidden");
+
+    // Switching user requires root
+    if std::fs::metadata(dir.path()).unwrap().uid() != 0 {
+        return;
+    }
+    std::os::unix::fs::chown(&path, Some(65534), None).unwrap();
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = suexec.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "65534\n");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sandbox() {
+    let dir = tempfile::tempdir().unwrap();
+    let secret_dir = tempfile::tempdir().unwrap();
+    let secret = secret_dir.path().join("secret.txt");
+    std::fs::write(&secret, "secret\n").unwrap();
+    let mut sandboxed = script(write_script(
+        &dir,
+        "sandbox.sh",
+        &format!(
+            "#!/bin/sh\necho 'Content-Type: text/plain'\necho\ncat {} || echo denied\n",
+            secret.to_string_lossy()
+        ),
+    ));
+    sandboxed.sandbox = Some(Sandbox {
+        read_only: ["/bin", "/usr", "/lib", "/lib64", "/etc/ld.so.cache"]
+            .into_iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect(),
+    });
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = sandboxed.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "denied\n");
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    // Orphans may stay zombies when nobody reaps them in a container
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => !stat.rsplit_once(") ").unwrap().1.starts_with('Z'),
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn kill_process_group() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("child.pid");
+    let mut killed = script(write_script(
+        &dir,
+        "group.sh",
+        &format!(
+            "#!/bin/sh\ntrap '' TERM\nsleep 30 &\necho $! > {}\necho 'Content-Type: text/plain'\necho\nwait\n",
+            pid_file.to_string_lossy()
+        ),
+    ));
+    killed.kill_grace = Some(std::time::Duration::from_millis(200));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = killed.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+    let child: u32 = std::fs::read_to_string(&pid_file)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(is_running(child));
+
+    // The client goes away, the script and its child ignore SIGTERM
+    drop(res);
+    let status = info.wait().await.unwrap();
+    assert_eq!(status.signal(), Some(9));
+    for _ in 0..50 {
+        if !is_running(child) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("child {} of the script is still running", child);
+}
+
+#[tokio::test]
+async fn header_timeout() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut slow = script(write_script(
+        &dir,
+        "slow.sh",
+        "#!/bin/sh\nsleep 30\necho 'Content-Type: text/plain'\necho\n",
+    ));
+    slow.header_timeout = Some(std::time::Duration::from_millis(100));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = slow.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+    drop(res);
+    assert!(!info.wait().await.unwrap().success());
+}
+
+#[tokio::test]
+async fn request_timeout() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut endless = script(write_script(
+        &dir,
+        "endless.sh",
+        "#!/bin/sh\necho 'Content-Type: text/plain'\necho\necho start\nsleep 30\necho end\n",
+    ));
+    endless.request_timeout = Some(std::time::Duration::from_millis(200));
+    let req = Request::builder()
+        .uri("/")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = endless.serve(req, remote(), Vec::new()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+    let mut body = res.into_body();
+    let frame = body.frame().await.unwrap().unwrap();
+    assert_eq!(frame.into_data().unwrap(), "start\n");
+    let err = body.frame().await.unwrap().unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{}", err);
+    drop(body);
+    assert!(!info.wait().await.unwrap().success());
+}
+
+#[tokio::test]
+async fn request_timeout_while_spooling() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut echo = script(write_script(&dir, "echo.sh", ECHO_SCRIPT));
+    echo.spool = Some(SpoolConfig::default());
+    echo.request_timeout = Some(std::time::Duration::from_millis(200));
+    // The body never ends
+    let frames = stream::iter([Ok::<_, std::convert::Infallible>(Frame::data(
+        Bytes::from_static(b"hello"),
+    ))])
+    .chain(stream::pending());
+    let req = Request::builder()
+        .method("POST")
+        .uri("/")
+        .header(TRANSFER_ENCODING, "chunked")
+        .body(StreamBody::new(frames))
+        .unwrap();
+    let res = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        echo.serve(req, remote(), Vec::new()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+}
+
+#[tokio::test]
+async fn request_body_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let reader = script(write_script(
+        &dir,
+        "reader.sh",
+        "#!/bin/sh\ncat > /dev/null\nsleep 30\n",
+    ));
+    for (kind, status) in [
+        (std::io::ErrorKind::TimedOut, StatusCode::REQUEST_TIMEOUT),
+        (std::io::ErrorKind::ConnectionReset, StatusCode::BAD_REQUEST),
+    ] {
+        let frames: Frames = vec![
+            Ok(Frame::data(Bytes::from_static(b"partial"))),
+            Err(std::io::Error::new(kind, "body failed")),
+        ];
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(CONTENT_LENGTH, "100")
+            .body(StreamBody::new(stream::iter(frames)))
+            .unwrap();
+        let res = reader.serve(req, remote(), Vec::new()).await.unwrap();
+        assert_eq!(res.status(), status);
+        let mut info = res.extensions().get::<ProcessInfo>().unwrap().clone();
+        drop(res);
+        info.wait().await.unwrap();
+    }
+}
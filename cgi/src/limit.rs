@@ -1,31 +1,50 @@
 use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{ready, Context, Poll},
+    time::Duration,
 };
 
+use cgi_rs::server::ConnectInfo;
 use core::future::Future;
 use hyper::{
-    body::{Body, Frame, Incoming},
-    Request, Response,
+    body::{Body, Frame},
+    header::{HeaderName, RETRY_AFTER},
+    Request, Response, StatusCode,
 };
 use pin_project::pin_project;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
-use tokio_util::sync::PollSemaphore;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
 use tower::Layer;
 
 #[pin_project]
 pub struct PermittedBody<B> {
     permit: Option<OwnedSemaphorePermit>,
     #[pin]
-    body: B,
+    body: Option<B>,
 }
 
 impl<B> PermittedBody<B> {
     pub fn new(permit: OwnedSemaphorePermit, body: B) -> PermittedBody<B> {
         PermittedBody {
             permit: Some(permit),
-            body,
+            body: Some(body),
+        }
+    }
+
+    /// Empty body of a rejected request
+    pub fn empty() -> PermittedBody<B> {
+        PermittedBody {
+            permit: None,
+            body: None,
         }
     }
 }
@@ -36,118 +55,243 @@ impl<B: Body> Body for PermittedBody<B> {
     type Error = B::Error;
 
     fn poll_frame(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        match self.as_mut().project().permit.take() {
-            Some(permit) => match self.as_mut().project().body.poll_frame(cx) {
-                Poll::Ready(None) => Poll::Ready(None),
-                poll => {
-                    *self.as_mut().project().permit = Some(permit);
-                    poll
+        let this = self.project();
+        match this.body.as_pin_mut() {
+            Some(body) => {
+                let poll = body.poll_frame(cx);
+                // Release the permit as soon as the body is complete
+                if let Poll::Ready(None) = poll {
+                    this.permit.take();
                 }
-            },
+                poll
+            }
             None => Poll::Ready(None),
         }
     }
 }
 
+/// Bounds of the queue of the requests waiting for a permit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    /// Max number of waiting requests, None for no limit
+    pub max_length: Option<usize>,
+    /// Max time waited for a permit, None for no limit
+    pub max_wait: Option<Duration>,
+    /// Delay sent in the `Retry-After` header of rejected requests
+    pub retry_after: Duration,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_length: None,
+            max_wait: None,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Queue depth and wait times of a [`GlobalHttpConcurrencyLimitLayer`].
+#[derive(Debug, Default)]
+pub struct LimitMetrics {
+    queued: AtomicUsize,
+    max_queued: AtomicUsize,
+    waited: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+    rejected_full: AtomicU64,
+    rejected_timeout: AtomicU64,
+}
+
+impl LimitMetrics {
+    /// Number of requests currently waiting for a permit
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Highest number of requests waiting at the same time
+    pub fn max_queue_depth(&self) -> usize {
+        self.max_queued.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests which got a permit after waiting
+    pub fn waited(&self) -> u64 {
+        self.waited.load(Ordering::Relaxed)
+    }
+
+    /// Mean wait of the requests which got a permit after waiting
+    pub fn mean_wait(&self) -> Duration {
+        match self.waited() {
+            0 => Duration::ZERO,
+            waited => Duration::from_micros(self.wait_micros.load(Ordering::Relaxed) / waited),
+        }
+    }
+
+    /// Longest wait of a request which got a permit
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_micros(self.max_wait_micros.load(Ordering::Relaxed))
+    }
+
+    /// Number of requests rejected because the queue was full
+    pub fn rejected_full(&self) -> u64 {
+        self.rejected_full.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests rejected after waiting too long
+    pub fn rejected_timeout(&self) -> u64 {
+        self.rejected_timeout.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request in the queue, unless it already holds `max_length`
+    /// requests. The check and the count are a single atomic update, so
+    /// concurrent requests cannot exceed the bound
+    fn enqueue(self: &Arc<Self>, max_length: Option<usize>) -> Option<QueueGuard> {
+        let queued = self
+            .queued
+            .fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |queued| match max_length {
+                    Some(max_length) if queued >= max_length => None,
+                    _ => Some(queued + 1),
+                },
+            )
+            .ok()?
+            + 1;
+        self.max_queued.fetch_max(queued, Ordering::Relaxed);
+        Some(QueueGuard(self.clone()))
+    }
+
+    fn record_wait(&self, wait: Duration) {
+        let micros = wait.as_micros() as u64;
+        self.waited.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_wait_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+impl Display for LimitMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "queue depth {} (max {}), {} waited {:?} on average (max {:?}), \
+             rejected {} on full queue and {} on wait timeout",
+            self.queue_depth(),
+            self.max_queue_depth(),
+            self.waited(),
+            self.mean_wait(),
+            self.max_wait(),
+            self.rejected_full(),
+            self.rejected_timeout(),
+        )
+    }
+}
+
+/// Counts a request in the queue until dropped
+struct QueueGuard(Arc<LimitMetrics>);
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub struct HttpConcurrencyLimit<S> {
     service: S,
-    semaphore: PollSemaphore,
-    /// The currently acquired semaphore permit, if there is sufficient
-    /// concurrency to send a new request.
-    ///
-    /// The permit is acquired in `poll_ready`, and taken in `call` when sending
-    /// a new request.
-    permit: Option<OwnedSemaphorePermit>,
+    semaphore: Arc<Semaphore>,
+    queue: QueueLimits,
+    metrics: Arc<LimitMetrics>,
 }
 
 impl<T: Clone> Clone for HttpConcurrencyLimit<T> {
     fn clone(&self) -> Self {
-        // Since we hold an `OwnedSemaphorePermit`, we can't derive `Clone`.
-        // Instead, when cloning the service, create a new service with the
-        // same semaphore, but with the permit in the un-acquired state.
         Self {
             service: self.service.clone(),
             semaphore: self.semaphore.clone(),
-            permit: None,
+            queue: self.queue,
+            metrics: self.metrics.clone(),
         }
     }
 }
 
-impl<S, B> tower::Service<Request<Incoming>> for HttpConcurrencyLimit<S>
+impl<S, ReqBody, B> tower::Service<Request<ReqBody>> for HttpConcurrencyLimit<S>
 where
-    S: tower::Service<Request<Incoming>, Response = Response<B>>,
+    S: tower::Service<Request<ReqBody>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
 {
     type Response = Response<PermittedBody<B>>;
 
     type Error = S::Error;
 
-    type Future = HttpConcurrencyLimitFut<S::Future>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, S::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        // If we haven't already acquired a permit from the semaphore, try to
-        // acquire one first.
-        if self.permit.is_none() {
-            self.permit = ready!(self.semaphore.poll_acquire(cx));
-            debug_assert!(
-                self.permit.is_some(),
-                "ConcurrencyLimit semaphore is never closed, so `poll_acquire` \
-                 should never fail",
-            );
-        }
-
-        // Once we've acquired a permit (or if we already had one), poll the
-        // inner service.
+        // The permit is waited for in the response future, where the
+        // request can be rejected once the queue is full.
         self.service.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
-        // Take the permit
-        let permit = self
-            .permit
-            .take()
-            .expect("max requests in-flight; poll_ready must be called first");
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // Take the service which is ready, leaving a clone in its place
+        let clone = self.service.clone();
+        let mut service = std::mem::replace(&mut self.service, clone);
+        let semaphore = self.semaphore.clone();
+        let queue = self.queue;
+        let metrics = self.metrics.clone();
 
-        // Call the inner service
-        let future = self.service.call(req);
+        Box::pin(async move {
+            let permit = match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let Some(_queued) = metrics.enqueue(queue.max_length) else {
+                        metrics.rejected_full.fetch_add(1, Ordering::Relaxed);
+                        return Ok(rejected(StatusCode::SERVICE_UNAVAILABLE, queue.retry_after));
+                    };
+                    let start = Instant::now();
+                    let acquire = semaphore.acquire_owned();
+                    let permit = match queue.max_wait {
+                        Some(max_wait) => match tokio::time::timeout(max_wait, acquire).await {
+                            Ok(permit) => permit,
+                            Err(_) => {
+                                metrics.rejected_timeout.fetch_add(1, Ordering::Relaxed);
+                                return Ok(rejected(
+                                    StatusCode::SERVICE_UNAVAILABLE,
+                                    queue.retry_after,
+                                ));
+                            }
+                        },
+                        None => acquire.await,
+                    };
+                    metrics.record_wait(start.elapsed());
+                    permit.expect("ConcurrencyLimit semaphore is never closed")
+                }
+            };
 
-        HttpConcurrencyLimitFut {
-            future,
-            permit: Some(permit),
-        }
+            let response = service.call(req).await?;
+            Ok(response.map(|body| PermittedBody::new(permit, body)))
+        })
     }
 }
 
-#[pin_project]
-pub struct HttpConcurrencyLimitFut<F> {
-    #[pin]
-    future: F,
-    permit: Option<OwnedSemaphorePermit>,
-}
-
-impl<F, B, E> Future for HttpConcurrencyLimitFut<F>
-where
-    F: Future<Output = Result<Response<B>, E>>,
-{
-    type Output = Result<Response<PermittedBody<B>>, E>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.as_mut().project().future.poll(cx) {
-            Poll::Ready(Ok(resp)) => match self.as_mut().project().permit.take() {
-                Some(permit) => Poll::Ready(Ok(resp.map(|body| PermittedBody::new(permit, body)))),
-                None => unreachable!("Future should not be polled twice"),
-            },
-            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
-            Poll::Pending => Poll::Pending,
-        }
-    }
+/// Response of a rejected request, telling when to retry
+fn rejected<B>(status: StatusCode, retry_after: Duration) -> Response<PermittedBody<B>> {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    Response::builder()
+        .status(status)
+        .header(RETRY_AFTER, seconds)
+        .body(PermittedBody::empty())
+        .unwrap()
 }
 
 #[derive(Debug, Clone)]
 pub struct GlobalHttpConcurrencyLimitLayer {
     semaphore: Arc<Semaphore>,
+    queue: QueueLimits,
+    metrics: Arc<LimitMetrics>,
 }
 
 impl GlobalHttpConcurrencyLimitLayer {
@@ -158,7 +302,22 @@ impl GlobalHttpConcurrencyLimitLayer {
 
     /// Create a new `GlobalConcurrencyLimitLayer` from a `Arc<Semaphore>`
     pub fn with_semaphore(semaphore: Arc<Semaphore>) -> Self {
-        GlobalHttpConcurrencyLimitLayer { semaphore }
+        GlobalHttpConcurrencyLimitLayer {
+            semaphore,
+            queue: QueueLimits::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Bounds the queue of the requests waiting for a permit, the requests
+    /// past the bounds get 503
+    pub fn with_queue(self, queue: QueueLimits) -> Self {
+        GlobalHttpConcurrencyLimitLayer { queue, ..self }
+    }
+
+    /// Metrics of the queue, shared by the services of the layer
+    pub fn metrics(&self) -> Arc<LimitMetrics> {
+        self.metrics.clone()
     }
 }
 
@@ -168,8 +327,530 @@ impl<S> Layer<S> for GlobalHttpConcurrencyLimitLayer {
     fn layer(&self, service: S) -> Self::Service {
         HttpConcurrencyLimit {
             service,
-            semaphore: PollSemaphore::new(self.semaphore.clone()),
-            permit: None,
+            semaphore: self.semaphore.clone(),
+            queue: self.queue,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// Key of the client of a request, for [`KeyedHttpConcurrencyLimitLayer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientKey {
+    /// IP address of the [`ConnectInfo`] extension. IPv6 clients are keyed
+    /// by their /64 prefix, which a single host can pick addresses from
+    RemoteIp,
+    /// Value of a header, such as an API key, falling back to the remote IP
+    /// when the header is missing.
+    ///
+    /// The value is trusted as is: the header must be set or validated
+    /// upstream, for instance by a proxy checking the API keys, otherwise a
+    /// client can send new values to get around its limit.
+    Header(HeaderName),
+}
+
+impl ClientKey {
+    fn of<B>(&self, req: &Request<B>) -> Option<String> {
+        let remote_ip = || {
+            req.extensions()
+                .get::<ConnectInfo>()
+                .map(|ConnectInfo(remote)| ip_key(remote.ip()))
+        };
+        match self {
+            ClientKey::RemoteIp => remote_ip(),
+            ClientKey::Header(name) => match req.headers().get(name) {
+                Some(value) => Some(format!(
+                    "{}: {}",
+                    name,
+                    String::from_utf8_lossy(value.as_bytes())
+                )),
+                None => remote_ip(),
+            },
         }
     }
 }
+
+/// Key of an IP address: IPv4 addresses, including the IPv4-mapped IPv6
+/// ones, as is and the /64 prefix of the IPv6 ones
+fn ip_key(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !u128::from(u64::MAX);
+            format!("{}/64", Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+/// Number of keys tracked by [`KeyedHttpConcurrencyLimitLayer::new`]
+pub const DEFAULT_MAX_KEYS: usize = 10_000;
+
+struct KeyEntry {
+    semaphore: Arc<Semaphore>,
+    last_used: Instant,
+    /// Position of the key in the use order
+    tick: u64,
+}
+
+impl KeyEntry {
+    /// Whether no permit holds the semaphore
+    fn is_free(&self) -> bool {
+        Arc::strong_count(&self.semaphore) == 1
+    }
+}
+
+/// Semaphores of the keys, evicted once idle
+struct KeySemaphores {
+    max: usize,
+    max_keys: usize,
+    idle_timeout: Duration,
+    entries: HashMap<String, KeyEntry>,
+    /// Keys by the tick of their last use, least recently used first
+    order: BTreeMap<u64, String>,
+    next_tick: u64,
+    last_sweep: Instant,
+}
+
+impl KeySemaphores {
+    /// Semaphore of a key. A new key evicts the least recently used free
+    /// key when `max_keys` are tracked, None if every key holds permits
+    fn semaphore(&mut self, key: String) -> Option<Arc<Semaphore>> {
+        let now = Instant::now();
+        if now >= self.last_sweep + self.idle_timeout {
+            self.sweep(now);
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.max_keys {
+            // Only the keys holding permits are skipped
+            let (&tick, lru) = self
+                .order
+                .iter()
+                .find(|(_, key)| self.entries[*key].is_free())?;
+            self.entries.remove(lru);
+            self.order.remove(&tick);
+        }
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        let max = self.max;
+        let entry = self.entries.entry(key.clone()).or_insert_with(|| KeyEntry {
+            semaphore: Arc::new(Semaphore::new(max)),
+            last_used: now,
+            tick,
+        });
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key);
+        entry.last_used = now;
+        entry.tick = tick;
+        Some(entry.semaphore.clone())
+    }
+
+    /// Forgets the idle keys: no permit holds their semaphore and they were
+    /// not used within the timeout
+    fn sweep(&mut self, now: Instant) {
+        self.last_sweep = now;
+        let idle_timeout = self.idle_timeout;
+        let idle: Vec<u64> = self
+            .order
+            .iter()
+            .map(|(&tick, key)| (tick, &self.entries[key]))
+            .take_while(|(_, entry)| now >= entry.last_used + idle_timeout)
+            .filter(|(_, entry)| entry.is_free())
+            .map(|(tick, _)| tick)
+            .collect();
+        for tick in idle {
+            if let Some(key) = self.order.remove(&tick) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+pub struct KeyedHttpConcurrencyLimit<S> {
+    service: S,
+    key: Option<ClientKey>,
+    retry_after: Duration,
+    keys: Arc<Mutex<KeySemaphores>>,
+}
+
+impl<T: Clone> Clone for KeyedHttpConcurrencyLimit<T> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            key: self.key.clone(),
+            retry_after: self.retry_after,
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+impl<S, ReqBody, B> tower::Service<Request<ReqBody>> for KeyedHttpConcurrencyLimit<S>
+where
+    S: tower::Service<Request<ReqBody>, Response = Response<B>>,
+{
+    type Response = Response<PermittedBody<B>>;
+
+    type Error = S::Error;
+
+    type Future = KeyedHttpConcurrencyLimitFut<S::Future, B>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // Requests without a key are only limited by the global limit
+        let permit = match self.key.as_ref().and_then(|key| key.of(&req)) {
+            Some(key) => {
+                let semaphore = self.keys.lock().unwrap().semaphore(key);
+                let permit = match semaphore {
+                    Some(semaphore) => semaphore
+                        .try_acquire_owned()
+                        .map_err(|_| rejected(StatusCode::TOO_MANY_REQUESTS, self.retry_after)),
+                    // Too many clients are being served
+                    None => Err(rejected(StatusCode::SERVICE_UNAVAILABLE, self.retry_after)),
+                };
+                match permit {
+                    Ok(permit) => Some(permit),
+                    Err(response) => {
+                        return KeyedHttpConcurrencyLimitFut::Rejected {
+                            response: Some(response),
+                        }
+                    }
+                }
+            }
+            None => None,
+        };
+        KeyedHttpConcurrencyLimitFut::Called {
+            future: self.service.call(req),
+            permit,
+        }
+    }
+}
+
+#[pin_project(project = KeyedHttpConcurrencyLimitFutProj)]
+pub enum KeyedHttpConcurrencyLimitFut<F, B> {
+    Called {
+        #[pin]
+        future: F,
+        permit: Option<OwnedSemaphorePermit>,
+    },
+    Rejected {
+        response: Option<Response<PermittedBody<B>>>,
+    },
+}
+
+impl<F, B, E> Future for KeyedHttpConcurrencyLimitFut<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<PermittedBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            KeyedHttpConcurrencyLimitFutProj::Called { future, permit } => {
+                let response = ready!(future.poll(cx))?;
+                let permit = permit.take();
+                Poll::Ready(Ok(response.map(|body| PermittedBody {
+                    permit,
+                    body: Some(body),
+                })))
+            }
+            KeyedHttpConcurrencyLimitFutProj::Rejected { response } => Poll::Ready(Ok(response
+                .take()
+                .expect("KeyedHttpConcurrencyLimitFut polled after completion"))),
+        }
+    }
+}
+
+/// Limits the number of requests served in parallel for each client, the
+/// requests past the limit get 429.
+///
+/// At most [`KeyedHttpConcurrencyLimitLayer::with_max_keys`] clients are
+/// tracked: a new client evicts the least recently used one without
+/// request in progress, or gets 503 if every client has one.
+///
+/// It is meant to be put in front of a [`GlobalHttpConcurrencyLimitLayer`],
+/// so that one client cannot take every permit of the global limit.
+#[derive(Clone)]
+pub struct KeyedHttpConcurrencyLimitLayer {
+    key: Option<ClientKey>,
+    retry_after: Duration,
+    keys: Arc<Mutex<KeySemaphores>>,
+}
+
+impl KeyedHttpConcurrencyLimitLayer {
+    /// Create a new `KeyedHttpConcurrencyLimitLayer` allowing `max` requests
+    /// in parallel for each key, keys are evicted after a minute idle and
+    /// at most [`DEFAULT_MAX_KEYS`] are tracked.
+    pub fn new(key: ClientKey, max: usize) -> Self {
+        KeyedHttpConcurrencyLimitLayer {
+            key: Some(key),
+            retry_after: Duration::from_secs(1),
+            keys: Arc::new(Mutex::new(KeySemaphores {
+                max,
+                max_keys: DEFAULT_MAX_KEYS,
+                idle_timeout: Duration::from_secs(60),
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_tick: 0,
+                last_sweep: Instant::now(),
+            })),
+        }
+    }
+
+    /// Create a `KeyedHttpConcurrencyLimitLayer` limiting no request.
+    pub fn unlimited() -> Self {
+        KeyedHttpConcurrencyLimitLayer {
+            key: None,
+            ..Self::new(ClientKey::RemoteIp, 0)
+        }
+    }
+
+    /// Time after which a key without request is forgotten, idle keys are
+    /// swept by the next request
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> Self {
+        self.keys.lock().unwrap().idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Max number of keys tracked at the same time
+    pub fn with_max_keys(self, max_keys: usize) -> Self {
+        self.keys.lock().unwrap().max_keys = max_keys;
+        self
+    }
+
+    /// Delay sent in the `Retry-After` header of rejected requests
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        KeyedHttpConcurrencyLimitLayer {
+            retry_after,
+            ..self
+        }
+    }
+
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.keys.lock().unwrap().entries.len()
+    }
+}
+
+impl<S> Layer<S> for KeyedHttpConcurrencyLimitLayer {
+    type Service = KeyedHttpConcurrencyLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        KeyedHttpConcurrencyLimit {
+            service,
+            key: self.key.clone(),
+            retry_after: self.retry_after,
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::BodyExt;
+    use tower::Service;
+
+    use super::*;
+
+    /// Service answering every request at once, its permit is held until
+    /// the body of the response is read
+    fn answer(_req: Request<()>) -> impl Future<Output = Result<Response<String>, Infallible>> {
+        std::future::ready(Ok(Response::new(String::from("ok"))))
+    }
+
+    fn limited(
+        layer: &GlobalHttpConcurrencyLimitLayer,
+    ) -> impl tower::Service<
+        Request<()>,
+        Response = Response<PermittedBody<String>>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<PermittedBody<String>>, Infallible>> + Send,
+    > {
+        layer.layer(tower::service_fn(answer))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queue() {
+        let layer = GlobalHttpConcurrencyLimitLayer::new(1).with_queue(QueueLimits {
+            max_length: Some(1),
+            max_wait: None,
+            retry_after: Duration::from_secs(3),
+        });
+        let mut service = limited(&layer);
+        let metrics = layer.metrics();
+
+        let served = service.call(Request::new(())).await.unwrap();
+        let queued = tokio::spawn(service.call(Request::new(())));
+        tokio::task::yield_now().await;
+        assert_eq!(metrics.queue_depth(), 1);
+
+        let rejected = service.call(Request::new(())).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejected.headers()[RETRY_AFTER], "3");
+        assert_eq!(metrics.rejected_full(), 1);
+
+        // Reading the body releases the permit
+        served.into_body().collect().await.unwrap();
+        let queued = queued.await.unwrap().unwrap();
+        assert_eq!(queued.status(), StatusCode::OK);
+        assert_eq!(metrics.queue_depth(), 0);
+        assert_eq!(metrics.max_queue_depth(), 1);
+        assert_eq!(metrics.waited(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_wait() {
+        let layer = GlobalHttpConcurrencyLimitLayer::new(1).with_queue(QueueLimits {
+            max_length: None,
+            max_wait: Some(Duration::from_secs(2)),
+            retry_after: Duration::from_secs(1),
+        });
+        let mut service = limited(&layer);
+        let metrics = layer.metrics();
+
+        let _served = service.call(Request::new(())).await.unwrap();
+        let start = tokio::time::Instant::now();
+        let rejected = service.call(Request::new(())).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejected.headers()[RETRY_AFTER], "1");
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(metrics.rejected_timeout(), 1);
+        assert_eq!(metrics.queue_depth(), 0);
+        assert_eq!(metrics.waited(), 0);
+    }
+
+    fn keyed(
+        layer: &KeyedHttpConcurrencyLimitLayer,
+    ) -> impl tower::Service<
+        Request<()>,
+        Response = Response<PermittedBody<String>>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<PermittedBody<String>>, Infallible>>,
+    > {
+        layer.layer(tower::service_fn(answer))
+    }
+
+    fn keyed_request(key: &str) -> Request<()> {
+        Request::builder()
+            .header("x-api-key", key)
+            .body(())
+            .unwrap()
+    }
+
+    fn api_key_layer() -> KeyedHttpConcurrencyLimitLayer {
+        KeyedHttpConcurrencyLimitLayer::new(
+            ClientKey::Header(HeaderName::from_static("x-api-key")),
+            1,
+        )
+        .with_retry_after(Duration::from_secs(2))
+    }
+
+    fn tracked(layer: &KeyedHttpConcurrencyLimitLayer) -> Vec<String> {
+        let semaphores = layer.keys.lock().unwrap();
+        let mut keys: Vec<String> = semaphores.entries.keys().cloned().collect();
+        keys.sort();
+        let mut ordered: Vec<String> = semaphores.order.values().cloned().collect();
+        ordered.sort();
+        assert_eq!(keys, ordered);
+        keys
+    }
+
+    #[tokio::test]
+    async fn per_key_limit() {
+        let layer = api_key_layer();
+        let mut service = keyed(&layer);
+
+        let served = service.call(keyed_request("a")).await.unwrap();
+        assert_eq!(served.status(), StatusCode::OK);
+        let rejected = service.call(keyed_request("a")).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rejected.headers()[RETRY_AFTER], "2");
+        // Other keys have their own limit
+        let other = service.call(keyed_request("b")).await.unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+        // Requests without key are not limited
+        let unkeyed = service.call(Request::new(())).await.unwrap();
+        assert_eq!(unkeyed.status(), StatusCode::OK);
+
+        served.into_body().collect().await.unwrap();
+        let served = service.call(keyed_request("a")).await.unwrap();
+        assert_eq!(served.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn active_key_survives_sweep() {
+        let layer = api_key_layer().with_idle_timeout(Duration::from_secs(1));
+        let mut service = keyed(&layer);
+
+        let served = service.call(keyed_request("a")).await.unwrap();
+        drop(service.call(keyed_request("b")).await.unwrap());
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        // The request of c sweeps b, a holds a permit
+        drop(service.call(keyed_request("c")).await.unwrap());
+        assert_eq!(tracked(&layer), ["x-api-key: a", "x-api-key: c"]);
+        let rejected = service.call(keyed_request("a")).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(served);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        drop(service.call(keyed_request("d")).await.unwrap());
+        assert_eq!(tracked(&layer), ["x-api-key: d"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn key_eviction() {
+        let layer = api_key_layer().with_max_keys(2);
+        let mut service = keyed(&layer);
+
+        drop(service.call(keyed_request("a")).await.unwrap());
+        tokio::time::advance(Duration::from_millis(10)).await;
+        let served = service.call(keyed_request("b")).await.unwrap();
+        tokio::time::advance(Duration::from_millis(10)).await;
+
+        // a is the least recently used key without permit
+        drop(service.call(keyed_request("c")).await.unwrap());
+        assert_eq!(tracked(&layer), ["x-api-key: b", "x-api-key: c"]);
+
+        // Every key holds a permit
+        let _served_c = service.call(keyed_request("c")).await.unwrap();
+        let rejected = service.call(keyed_request("d")).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(tracked(&layer), ["x-api-key: b", "x-api-key: c"]);
+
+        drop(served);
+        let served = service.call(keyed_request("d")).await.unwrap();
+        assert_eq!(served.status(), StatusCode::OK);
+        assert_eq!(tracked(&layer), ["x-api-key: c", "x-api-key: d"]);
+    }
+
+    #[test]
+    fn remote_ip_key() {
+        let key = |remote: &str| {
+            let mut req = Request::new(());
+            req.extensions_mut()
+                .insert(ConnectInfo(remote.parse().unwrap()));
+            ClientKey::RemoteIp.of(&req).unwrap()
+        };
+        assert_eq!(key("192.0.2.1:80"), "192.0.2.1");
+        assert_eq!(key("[::ffff:192.0.2.1]:80"), "192.0.2.1");
+        assert_eq!(key("[2001:db8:0:1::1]:80"), "2001:db8:0:1::/64");
+        assert_eq!(key("[2001:db8:0:1:a:b:c:d]:80"), "2001:db8:0:1::/64");
+        assert_eq!(key("[2001:db8:0:2::1]:80"), "2001:db8:0:2::/64");
+    }
+
+    #[test]
+    fn queue_bound() {
+        let metrics = Arc::new(LimitMetrics::default());
+        let first = metrics.enqueue(Some(2));
+        let second = metrics.enqueue(Some(2));
+        assert!(first.is_some() && second.is_some());
+        assert!(metrics.enqueue(Some(2)).is_none());
+        drop(first);
+        assert!(metrics.enqueue(Some(2)).is_some());
+        assert_eq!(metrics.queue_depth(), 1);
+        assert_eq!(metrics.max_queue_depth(), 2);
+    }
+}
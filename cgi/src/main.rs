@@ -1,20 +1,31 @@
 mod limit;
 mod timeout;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use cgi_rs::server::Script;
+use cgi_rs::server::{
+    serve_fastcgi_connection, BackendAddress, ConnectInfo, FastCgiBackend, FastCgiPool,
+    FastCgiWrap, FileSink, Interpreter, IoPriority, LocalRedirect, NphMode, ParseConfig, ParseMode,
+    ResourceLimits, RunAs, Sandbox, ScgiBackend, Script, ScriptAlias, ScriptRouter, SpoolConfig,
+};
+use hyper::header::HeaderName;
 use hyper::server::conn::http1;
 use hyper_util::rt::TokioIo;
-use limit::GlobalHttpConcurrencyLimitLayer;
-use timeout::RequestBodyTimeoutLayer;
-use tokio::io::{stderr, AsyncWrite, Stderr};
+use limit::{
+    ClientKey, GlobalHttpConcurrencyLimitLayer, KeyedHttpConcurrencyLimitLayer, QueueLimits,
+    DEFAULT_MAX_KEYS,
+};
+use log::{info, LevelFilter, Log, Metadata, Record};
+use timeout::{BodyTimeouts, MinThroughput, RequestBodyTimeoutLayer, ResponseBodyTimeoutLayer};
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tower::ServiceBuilder;
-use tower_http::timeout::ResponseBodyTimeoutLayer;
+use tower_http::add_extension::AddExtensionLayer;
 
 use clap::Parser;
 
@@ -42,42 +53,472 @@ struct Args {
     #[arg(long = "res-body-timeout")]
     response_body_timeout: Option<u64>,
 
+    /// Min rate in bytes per second of the request and response bodies, measured over
+    /// --body-rate-window of time spent waiting on them
+    #[arg(long = "min-body-rate")]
+    min_body_rate: Option<u64>,
+
+    /// Sliding window in millisecond of --min-body-rate (default "10000")
+    #[arg(long = "body-rate-window", requires = "min_body_rate")]
+    body_rate_window: Option<u64>,
+
+    /// Max time in millisecond to receive the whole request body
+    #[arg(long = "req-body-deadline")]
+    request_body_deadline: Option<u64>,
+
+    /// Max time in millisecond to send the whole response body
+    #[arg(long = "res-body-deadline")]
+    response_body_deadline: Option<u64>,
+
+    /// Time in millisecond given to a cancelled script to exit after SIGTERM, before its process
+    /// group is killed (default "5000")
+    #[arg(long = "kill-grace")]
+    kill_grace: Option<u64>,
+
+    /// Time in millisecond given to the script to send its header block, answering 504 after
+    #[arg(long = "header-timeout", conflicts_with_all = ["fastcgi_app", "scgi_app"])]
+    header_timeout: Option<u64>,
+
+    /// Deadline in millisecond of the whole request, after which the script is terminated
+    #[arg(long = "request-timeout", conflicts_with_all = ["fastcgi_app", "scgi_app"])]
+    request_timeout: Option<u64>,
+
     /// Max number of parallel processes (default "4")
     #[arg(long = "max-processes")]
     max_processes: Option<u16>,
 
-    /// Path of cgi script
-    path: PathBuf,
+    /// Max number of requests waiting for a process, answering 503 past it
+    #[arg(long = "max-queue")]
+    max_queue: Option<usize>,
+
+    /// Max time in millisecond a request waits for a process, answering 503 after
+    #[arg(long = "max-queue-wait")]
+    max_queue_wait: Option<u64>,
+
+    /// Retry-After in second of the 503 responses of the queue (default "1")
+    #[arg(long = "retry-after")]
+    retry_after: Option<u64>,
+
+    /// Max number of parallel processes of a client, answering 429 past it
+    #[arg(long = "max-per-client")]
+    max_per_client: Option<usize>,
+
+    /// Header identifying the client for --max-per-client, such as an API key (default the remote
+    /// IP, or its /64 prefix for IPv6). It is trusted as is, it must be set or checked by a proxy
+    /// in front of the server
+    #[arg(long = "client-key-header", requires = "max_per_client")]
+    client_key_header: Option<String>,
+
+    /// Max number of clients tracked by --max-per-client, new clients evict the least recently
+    /// used idle one or get 503 (default "10000")
+    #[arg(long = "max-clients", requires = "max_per_client")]
+    max_clients: Option<usize>,
+
+    /// Time in millisecond after which an idle client of --max-per-client is forgotten (default
+    /// "60000")
+    #[arg(long = "client-idle", requires = "max_per_client")]
+    client_idle: Option<u64>,
+
+    /// Interval in millisecond between two logs of the queue and client metrics
+    #[arg(long = "queue-stats", value_parser = clap::value_parser!(u64).range(1..))]
+    queue_stats: Option<u64>,
+
+    /// Spool request bodies without Content-Length before running the script
+    #[arg(long)]
+    spool: bool,
+
+    /// Max size in bytes of a spooled body kept in memory (default "1048576")
+    #[arg(long = "spool-memory")]
+    spool_memory: Option<usize>,
+
+    /// Max size in bytes of a spooled body (default "104857600")
+    #[arg(long = "spool-max-size")]
+    spool_max_size: Option<u64>,
+
+    /// Directory of spooled bodies (default system temporary directory)
+    #[arg(long = "spool-dir")]
+    spool_dir: Option<PathBuf>,
+
+    /// Relay the script output as non-parsed headers (default auto-detected from the "nph-" prefix)
+    #[arg(long)]
+    nph: bool,
+
+    /// Serve local redirect responses of the script internally instead of sending 302
+    #[arg(long = "local-redirect")]
+    local_redirect: bool,
+
+    /// Reject malformed script headers with 502 instead of ignoring them
+    #[arg(long)]
+    strict: bool,
+
+    /// File receiving the stderr lines of the script prefixed by their request (default stderr)
+    #[arg(long = "stderr-file")]
+    stderr_file: Option<PathBuf>,
+
+    /// Directory receiving the stderr lines of each script in its own file, named after the
+    /// script
+    #[arg(long = "stderr-dir", conflicts_with = "stderr_file")]
+    stderr_dir: Option<PathBuf>,
+
+    /// Serve the executables of the directory given as path, picked by the first path segment after root
+    #[arg(long = "cgi-bin")]
+    cgi_bin: bool,
+
+    /// Interpreter of the scripts with an extension, as EXT=INTERPRETER (repeatable)
+    #[arg(long = "handler")]
+    handlers: Vec<String>,
+
+    /// Other script served under a path prefix, as PREFIX=PATH (repeatable)
+    #[arg(
+        long = "route",
+        conflicts_with_all = ["cgi_bin", "fastcgi_app", "scgi_app", "fastcgi"]
+    )]
+    routes: Vec<String>,
+
+    /// Forward the requests to a FastCGI application listening on HOST:PORT or unix:PATH,
+    /// path is only given as SCRIPT_FILENAME
+    #[arg(long = "fastcgi-app")]
+    fastcgi_app: Option<String>,
+
+    /// Idle connections kept open to the FastCGI application (default 16, 0 to close them)
+    #[arg(long = "fastcgi-app-idle", requires = "fastcgi_app")]
+    fastcgi_app_idle: Option<usize>,
+
+    /// Forward the requests to an SCGI application listening on HOST:PORT or unix:PATH,
+    /// path is only given as SCRIPT_FILENAME. Bodies without Content-Length need --spool
+    #[arg(long = "scgi-app", conflicts_with = "fastcgi_app")]
+    scgi_app: Option<String>,
+
+    /// CPU time limit of the script in seconds
+    #[arg(long = "rlimit-cpu")]
+    rlimit_cpu: Option<u64>,
+
+    /// Address space limit of the script in bytes
+    #[arg(long = "rlimit-as")]
+    rlimit_as: Option<u64>,
+
+    /// Max number of open files of the script
+    #[arg(long = "rlimit-nofile")]
+    rlimit_nofile: Option<u64>,
+
+    /// Max number of processes of the user running the script
+    #[arg(long = "rlimit-nproc")]
+    rlimit_nproc: Option<u64>,
+
+    /// Max size in bytes of the core dumps of the script
+    #[arg(long = "rlimit-core")]
+    rlimit_core: Option<u64>,
+
+    /// Max size in bytes of the files written by the script
+    #[arg(long = "rlimit-fsize")]
+    rlimit_fsize: Option<u64>,
+
+    /// Nice value of the script, from -20 to 19
+    #[arg(long, allow_hyphen_values = true)]
+    nice: Option<i32>,
+
+    /// I/O scheduling of the script: idle, best-effort[:LEVEL] or realtime[:LEVEL]
+    #[arg(long)]
+    ionice: Option<IoPriority>,
+
+    /// Run the script as this user id, after suEXEC-like checks (requires running as root)
+    #[arg(long, requires_all = ["gid", "suexec_dirs"])]
+    uid: Option<u32>,
+
+    /// Group id of the script run with --uid
+    #[arg(long, requires = "uid")]
+    gid: Option<u32>,
+
+    /// Supplementary group ids of the script run with --uid, comma separated
+    #[arg(long, value_delimiter = ',', requires = "uid")]
+    groups: Vec<u32>,
+
+    /// Directory the scripts run with --uid must live under (repeatable)
+    #[arg(long = "suexec-dir", requires = "uid")]
+    suexec_dirs: Vec<PathBuf>,
+
+    /// Sandbox the script with Landlock and seccomp (Linux only): it can only read its directory
+    /// and the --sandbox-read paths
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Path the sandboxed script can read and execute, such as /usr or /lib (repeatable)
+    #[arg(long = "sandbox-read", requires = "sandbox")]
+    sandbox_read: Vec<PathBuf>,
+
+    /// Accept FastCGI connections from a web server on the binding address (HOST:PORT or
+    /// unix:PATH) and run the scripts named by their SCRIPT_FILENAME param, as fcgiwrap does
+    #[arg(long)]
+    fastcgi: bool,
+
+    /// Directory the scripts run with --fastcgi must be in, other SCRIPT_FILENAME are refused.
+    /// Required with --fastcgi
+    #[arg(
+        long = "fastcgi-root",
+        requires = "fastcgi",
+        required_if_eq("fastcgi", "true")
+    )]
+    fastcgi_root: Option<PathBuf>,
+
+    /// Path of cgi script, serving the requests matched by no route
+    #[arg(required_unless_present = "fastcgi")]
+    path: Option<PathBuf>,
 }
 
+/// Prints the logs of the server and of the library to stderr
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(LevelFilter::Info))
+        .expect("the logger is only set here");
     let binding_address = args.address.as_deref().unwrap_or("0.0.0.0:8080");
-    let addr = SocketAddr::from_str(binding_address).expect(&format!(
-        "Cannot parse {} as binding address",
-        &binding_address
-    ));
-    let script = Script {
-        path: args.path,
+    let rlimits = ResourceLimits {
+        cpu_seconds: args.rlimit_cpu,
+        address_space: args.rlimit_as,
+        open_files: args.rlimit_nofile,
+        processes: args.rlimit_nproc,
+        core_size: args.rlimit_core,
+        file_size: args.rlimit_fsize,
+        nice: args.nice,
+        io_priority: args.ionice,
+    };
+    let mut script = Script {
+        path: args.path.unwrap_or_default(),
         root: args.root.unwrap_or(PathBuf::new()),
         dir: args.dir,
         env: Vec::new(),
         args: Vec::new(),
         inherited_env: Vec::new(),
+        spool: if args.spool {
+            let default = SpoolConfig::default();
+            Some(SpoolConfig {
+                memory_threshold: args.spool_memory.unwrap_or(default.memory_threshold),
+                max_size: args.spool_max_size.unwrap_or(default.max_size),
+                temp_dir: args.spool_dir,
+            })
+        } else {
+            None
+        },
+        nph: if args.nph {
+            NphMode::Enabled
+        } else {
+            NphMode::Auto
+        },
+        local_redirect: if args.local_redirect {
+            Some(LocalRedirect::SameScript)
+        } else {
+            None
+        },
+        parse_config: ParseConfig {
+            mode: if args.strict {
+                ParseMode::Strict
+            } else {
+                ParseMode::Lenient
+            },
+            ..ParseConfig::default()
+        },
+        error_handler: None,
+        stderr_sink: match (&args.stderr_file, &args.stderr_dir) {
+            (Some(path), _) => Some(Arc::new(FileSink::open(path)?)),
+            (None, Some(dir)) => Some(Arc::new(FileSink::per_script(dir)?)),
+            (None, None) => None,
+        },
+        interpreters: HashMap::new(),
+        rlimits: (rlimits != ResourceLimits::default()).then_some(rlimits),
+        run_as: args.uid.map(|uid| RunAs {
+            uid,
+            gid: args.gid.unwrap_or_default(),
+            groups: args.groups,
+            allowed_dirs: args.suexec_dirs,
+        }),
+        sandbox: args.sandbox.then_some(Sandbox {
+            read_only: args.sandbox_read,
+        }),
+        kill_grace: args.kill_grace.map(Duration::from_millis),
+        header_timeout: args.header_timeout.map(Duration::from_millis),
+        request_timeout: args.request_timeout.map(Duration::from_millis),
     };
+    for handler in &args.handlers {
+        let (extension, interpreter) = handler.split_once('=').ok_or_else(|| {
+            format!(
+                "Cannot parse {} as handler, expected EXT=INTERPRETER",
+                handler
+            )
+        })?;
+        script.interpreters.insert(
+            extension.trim_start_matches('.').to_string(),
+            Interpreter::new(interpreter),
+        );
+    }
     //let semaphore = Arc::new(Semaphore::new(1));
     // let concurrence_layer = GlobalConcurrencyLimitLayer::new(1);
-    let concurrence_layer =
-        GlobalHttpConcurrencyLimitLayer::new(args.max_processes.unwrap_or(4).into());
+    let concurrence_layer = GlobalHttpConcurrencyLimitLayer::new(
+        args.max_processes.unwrap_or(4).into(),
+    )
+    .with_queue(QueueLimits {
+        max_length: args.max_queue,
+        max_wait: args.max_queue_wait.map(Duration::from_millis),
+        retry_after: Duration::from_secs(args.retry_after.unwrap_or(1)),
+    });
+    let client_layer = match args.max_per_client {
+        Some(max) => {
+            let key = match &args.client_key_header {
+                Some(header) => ClientKey::Header(HeaderName::from_str(header)?),
+                None => ClientKey::RemoteIp,
+            };
+            KeyedHttpConcurrencyLimitLayer::new(key, max)
+                .with_idle_timeout(Duration::from_millis(args.client_idle.unwrap_or(60000)))
+                .with_max_keys(args.max_clients.unwrap_or(DEFAULT_MAX_KEYS))
+                .with_retry_after(Duration::from_secs(args.retry_after.unwrap_or(1)))
+        }
+        None => KeyedHttpConcurrencyLimitLayer::unlimited(),
+    };
+    if let Some(interval) = args.queue_stats {
+        let metrics = concurrence_layer.metrics();
+        let client_layer = client_layer.clone();
+        let mut interval = tokio::time::interval(Duration::from_millis(interval));
+        tokio::task::spawn(async move {
+            loop {
+                interval.tick().await;
+                info!(
+                    "Queue: {}, {} clients tracked",
+                    metrics,
+                    client_layer.tracked_keys()
+                );
+            }
+        });
+    }
+
+    let min_throughput = args.min_body_rate.map(|bytes_per_second| MinThroughput {
+        bytes_per_second,
+        window: Duration::from_millis(args.body_rate_window.unwrap_or(10000)),
+    });
+    let request_body_timeout = BodyTimeouts {
+        idle: Duration::from_millis(args.request_body_timeout.unwrap_or(30000)),
+        min_throughput,
+        deadline: args.request_body_deadline.map(Duration::from_millis),
+    };
+    let response_body_timeout = BodyTimeouts {
+        idle: Duration::from_millis(args.response_body_timeout.unwrap_or(30000)),
+        min_throughput,
+        deadline: args.response_body_deadline.map(Duration::from_millis),
+    };
+
+    if args.fastcgi {
+        let service = FastCgiWrap {
+            script,
+            allowed_root: args
+                .fastcgi_root
+                .clone()
+                .expect("--fastcgi-root is required with --fastcgi"),
+        }
+        .service();
+        let layers = ServiceBuilder::new()
+            .layer(client_layer)
+            .layer(concurrence_layer)
+            .layer(RequestBodyTimeoutLayer::new(request_body_timeout))
+            .layer(ResponseBodyTimeoutLayer::new(response_body_timeout));
+        #[cfg(unix)]
+        if let Some(path) = binding_address.strip_prefix("unix:") {
+            let listener = UnixListener::bind(path)?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let service = layers.service(service.clone());
+                tokio::task::spawn(async move {
+                    if let Err(err) = serve_fastcgi_connection(stream, service).await {
+                        println!("Error serving FastCGI connection: {:?}", err);
+                    }
+                });
+            }
+        }
+        let listener = TcpListener::bind(binding_address).await?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let service = layers.service(service.clone());
+            tokio::task::spawn(async move {
+                if let Err(err) = serve_fastcgi_connection(stream, service).await {
+                    println!("Error serving FastCGI connection: {:?}", err);
+                }
+            });
+        }
+    }
+
+    let addr = SocketAddr::from_str(binding_address)
+        .unwrap_or_else(|_| panic!("Cannot parse {} as binding address", &binding_address));
 
     // We create a TcpListener and bind it to 127.0.0.1:3000
     let listener = TcpListener::bind(addr).await?;
 
+    let service = if let Some(address) = &args.fastcgi_app {
+        FastCgiBackend {
+            address: BackendAddress::from_str(address)?,
+            script,
+            pool: args
+                .fastcgi_app_idle
+                .map_or_else(FastCgiPool::default, FastCgiPool::new),
+        }
+        .service()
+    } else if let Some(address) = &args.scgi_app {
+        ScgiBackend {
+            address: BackendAddress::from_str(address)?,
+            script,
+        }
+        .service()
+    } else if args.cgi_bin {
+        ScriptAlias {
+            prefix: script.root.to_string_lossy().to_string(),
+            dir: script.path.clone(),
+            script: Script {
+                dir: None,
+                ..script
+            },
+        }
+        .service()
+    } else if args.routes.is_empty() {
+        script.service()
+    } else {
+        let mut router = ScriptRouter::new();
+        for route in &args.routes {
+            let (prefix, path) = route
+                .split_once('=')
+                .ok_or_else(|| format!("Cannot parse {} as route, expected PREFIX=PATH", route))?;
+            router = router.prefix(
+                prefix,
+                Script {
+                    path: PathBuf::from(path),
+                    root: PathBuf::from(prefix),
+                    dir: None,
+                    ..script.clone()
+                },
+            );
+        }
+        router.fallback(script).service()
+    };
+
     // We start a loop to continuously accept incoming connections
     loop {
-        let script = script.clone();
+        let service = service.clone();
         //let semaphore = semaphore.clone();
+        let client_layer = client_layer.clone();
         let concurrence_layer = concurrence_layer.clone();
         let (stream, remote) = listener.accept().await?;
 
@@ -87,20 +528,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
             let service = ServiceBuilder::new()
+                .layer(AddExtensionLayer::new(ConnectInfo(remote)))
+                .layer(client_layer)
                 .layer(concurrence_layer)
-                .layer(RequestBodyTimeoutLayer::new(Duration::from_millis(
-                    args.request_body_timeout.unwrap_or(30000),
-                )))
-                .layer(ResponseBodyTimeoutLayer::new(Duration::from_millis(
-                    args.response_body_timeout.unwrap_or(30000),
-                )))
-                //.service_fn(handle);
-                .service_fn(|req| script.serve(req, remote, ClonableStderr::new()));
-            //.service(script.service(remote));
+                .layer(RequestBodyTimeoutLayer::new(request_body_timeout))
+                .layer(ResponseBodyTimeoutLayer::new(response_body_timeout))
+                .service(service);
             // Finally, we bind the incoming connection to our `hello` service
             if let Err(err) = http1::Builder::new()
                 // `service_fn` converts our function in a `Service`
-                //.serve_connection(io, script.service_hyper(remote))
                 // .serve_connection(
                 //     io,
                 //     service_fn(|req| async {
@@ -119,41 +555,3 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         });
     }
 }
-
-struct ClonableStderr(Stderr);
-
-impl ClonableStderr {
-    fn new() -> ClonableStderr {
-        ClonableStderr(stderr())
-    }
-}
-
-impl Clone for ClonableStderr {
-    fn clone(&self) -> Self {
-        Self(stderr())
-    }
-}
-
-impl AsyncWrite for ClonableStderr {
-    fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.0).poll_write(cx, buf)
-    }
-
-    fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.0).poll_flush(cx)
-    }
-
-    fn poll_shutdown(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.0).poll_shutdown(cx)
-    }
-}
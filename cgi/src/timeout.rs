@@ -1,41 +1,189 @@
 use std::{
+    collections::VecDeque,
     pin::Pin,
     task::{ready, Context, Poll},
     time::Duration,
 };
 
 use futures::Future;
-use hyper::{body::Body, Request};
+use hyper::{
+    body::{Body, Buf},
+    Request, Response,
+};
 use pin_project::pin_project;
-use tokio::time::{sleep, Sleep};
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
 use tower::{BoxError, Layer, Service};
 
-/// Error for [`TimeoutBody`].
-#[derive(Debug)]
-pub struct TimeoutError(());
+/// Error for [`TimeoutBody`], telling which limit tripped.
+///
+/// It is wrapped in an [`std::io::Error`] of kind `TimedOut`, which scripts
+/// answer with 408 when they have not sent their header yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutError {
+    /// No frame within the idle timeout
+    Idle(Duration),
+    /// Less bytes than the minimum throughput over its window
+    Throughput(MinThroughput),
+    /// Body not complete within its total deadline
+    Deadline(Duration),
+}
 
 impl std::error::Error for TimeoutError {}
 
 impl std::fmt::Display for TimeoutError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "data was not received within the designated timeout")
+        match self {
+            TimeoutError::Idle(timeout) => write!(
+                f,
+                "data was not received within the designated timeout of {:?}",
+                timeout
+            ),
+            TimeoutError::Throughput(min) => write!(
+                f,
+                "data was received slower than {} bytes per second over {:?}",
+                min.bytes_per_second, min.window
+            ),
+            TimeoutError::Deadline(deadline) => {
+                write!(
+                    f,
+                    "body was not complete within the deadline of {:?}",
+                    deadline
+                )
+            }
+        }
+    }
+}
+
+fn timed_out(err: TimeoutError) -> BoxError {
+    Box::new(std::io::Error::new(std::io::ErrorKind::TimedOut, err))
+}
+
+/// Minimum rate of a body, measured over a sliding window.
+///
+/// Only the time spent waiting on the body counts: a consumer which stops
+/// polling the body does not lower its rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinThroughput {
+    pub bytes_per_second: u64,
+    /// Duration of the sliding window, also given to the body to start
+    pub window: Duration,
+}
+
+/// Limits of a [`TimeoutBody`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyTimeouts {
+    /// Max time between two frames, from the poll of the next one
+    pub idle: Duration,
+    /// Min rate of the data frames
+    pub min_throughput: Option<MinThroughput>,
+    /// Max time to receive the whole body, from its first poll
+    pub deadline: Option<Duration>,
+}
+
+/// Number of samples of a throughput window, received bytes are summed in
+/// samples to bound memory
+const SAMPLES: u32 = 16;
+
+/// Bytes received over the sliding window of a [`MinThroughput`].
+///
+/// Times are measured on a clock running only while the body is waited
+/// on, from a poll returning pending until the next frame.
+struct Throughput {
+    min: MinThroughput,
+    /// Time waited on the body before the current wait
+    waited: Duration,
+    /// Beginning of the current wait
+    waiting_since: Option<Instant>,
+    samples: VecDeque<(Duration, u64)>,
+    next_check: Duration,
+    check: Pin<Box<Sleep>>,
+}
+
+impl Throughput {
+    fn new(min: MinThroughput) -> Self {
+        Throughput {
+            min,
+            waited: Duration::ZERO,
+            waiting_since: None,
+            samples: VecDeque::new(),
+            next_check: min.window,
+            check: Box::pin(sleep(min.window)),
+        }
+    }
+
+    /// Time waited on the body
+    fn elapsed(&self, now: Instant) -> Duration {
+        self.waited
+            + self
+                .waiting_since
+                .map_or(Duration::ZERO, |since| now - since)
+    }
+
+    /// Ends the current wait with a frame of `bytes` data
+    fn record(&mut self, now: Instant, bytes: u64) {
+        self.waited = self.elapsed(now);
+        self.waiting_since = None;
+        let time = self.waited;
+        match self.samples.back_mut() {
+            Some((sample, sum)) if time < *sample + self.min.window / SAMPLES => *sum += bytes,
+            _ => self.samples.push_back((time, bytes)),
+        }
+    }
+
+    /// Starts waiting on the body, checking the rate when the check is due
+    /// then scheduling the next one: when the oldest sample leaves the
+    /// window, the rate may go too low
+    fn poll_check(&mut self, cx: &mut Context<'_>) -> Result<(), TimeoutError> {
+        self.waiting_since.get_or_insert_with(Instant::now);
+        loop {
+            let now = Instant::now();
+            let elapsed = self.elapsed(now);
+            if elapsed < self.next_check {
+                self.check.as_mut().reset(now + (self.next_check - elapsed));
+                if self.check.as_mut().poll(cx).is_pending() {
+                    return Ok(());
+                }
+                continue;
+            }
+            while let Some((time, _)) = self.samples.front() {
+                if *time + self.min.window > elapsed {
+                    break;
+                }
+                self.samples.pop_front();
+            }
+            let received: u64 = self.samples.iter().map(|(_, bytes)| bytes).sum();
+            let expected = self.min.bytes_per_second as f64 * self.min.window.as_secs_f64();
+            if (received as f64) < expected {
+                return Err(TimeoutError::Throughput(self.min));
+            }
+            self.next_check = match self.samples.front() {
+                Some((time, _)) => *time + self.min.window,
+                None => elapsed + self.min.window,
+            };
+        }
     }
 }
 
 #[pin_project]
 pub struct TimeoutBody<B> {
-    timeout: Duration,
+    timeouts: BodyTimeouts,
     sleep: Option<Pin<Box<Sleep>>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    throughput: Option<Throughput>,
+    started: bool,
     #[pin]
     body: B,
 }
 
 impl<B> TimeoutBody<B> {
     /// Creates a new [`TimeoutBody`].
-    pub fn new(timeout: Duration, body: B) -> Self {
+    pub fn new(timeouts: BodyTimeouts, body: B) -> Self {
         TimeoutBody {
-            timeout,
+            timeouts,
             sleep: None,
+            deadline: None,
+            throughput: None,
+            started: false,
             body,
         }
     }
@@ -53,18 +201,42 @@ where
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
-        let mut this = self.project();
+        let this = self.project();
+
+        // Start the total deadline and the throughput window on the first poll.
+        if !*this.started {
+            *this.started = true;
+            let now = Instant::now();
+            *this.deadline = this
+                .timeouts
+                .deadline
+                .map(|deadline| Box::pin(sleep_until(now + deadline)));
+            *this.throughput = this.timeouts.min_throughput.map(Throughput::new);
+        }
+
+        if let Some(deadline) = this.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                let deadline = this.timeouts.deadline.unwrap_or_default();
+                return Poll::Ready(Some(Err(timed_out(TimeoutError::Deadline(deadline)))));
+            }
+        }
+
+        if let Some(throughput) = this.throughput.as_mut() {
+            if let Err(err) = throughput.poll_check(cx) {
+                return Poll::Ready(Some(Err(timed_out(err))));
+            }
+        }
 
         // Start the `Sleep` if not active.
         if this.sleep.is_none() {
-            *this.sleep = Some(Box::pin(sleep(*this.timeout)));
+            *this.sleep = Some(Box::pin(sleep(this.timeouts.idle)));
         }
 
         let sleep_pinned = this.sleep.as_mut().map(|p| p.as_mut()).unwrap();
 
         // Error if the timeout has expired.
         if let Poll::Ready(()) = sleep_pinned.poll(cx) {
-            return Poll::Ready(Some(Err(Box::new(TimeoutError(())))));
+            return Poll::Ready(Some(Err(timed_out(TimeoutError::Idle(this.timeouts.idle)))));
         }
 
         // Check for body data.
@@ -72,23 +244,40 @@ where
         // A frame is ready. Reset the `Sleep`...
         *this.sleep = None;
 
+        // ...and count its data.
+        if let Some(throughput) = this.throughput.as_mut() {
+            let bytes = match &frame {
+                Some(Ok(frame)) => frame.data_ref().map_or(0, |data| data.remaining()),
+                _ => 0,
+            };
+            throughput.record(Instant::now(), bytes as u64);
+        }
+
         Poll::Ready(frame.transpose().map_err(Into::into).transpose())
     }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
 }
 
 /// Applies a [`TimeoutBody`] to the request body.
 #[derive(Clone, Debug)]
 pub struct RequestBodyTimeout<S> {
     inner: S,
-    timeout: Duration,
+    timeouts: BodyTimeouts,
 }
 
 impl<S> RequestBodyTimeout<S> {
     /// Creates a new [`RequestBodyTimeout`].
-    pub fn new(service: S, timeout: Duration) -> Self {
+    pub fn new(service: S, timeouts: BodyTimeouts) -> Self {
         Self {
             inner: service,
-            timeout,
+            timeouts,
         }
     }
 }
@@ -107,7 +296,7 @@ where
     }
 
     fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
-        let req = req.map(|body| TimeoutBody::new(self.timeout, body));
+        let req = req.map(|body| TimeoutBody::new(self.timeouts, body));
         self.inner.call(req)
     }
 }
@@ -115,13 +304,13 @@ where
 /// Applies a [`TimeoutBody`] to the request body.
 #[derive(Clone, Debug)]
 pub struct RequestBodyTimeoutLayer {
-    timeout: Duration,
+    timeouts: BodyTimeouts,
 }
 
 impl RequestBodyTimeoutLayer {
     /// Creates a new [`RequestBodyTimeoutLayer`].
-    pub fn new(timeout: Duration) -> Self {
-        Self { timeout }
+    pub fn new(timeouts: BodyTimeouts) -> Self {
+        Self { timeouts }
     }
 }
 
@@ -129,6 +318,203 @@ impl<S> Layer<S> for RequestBodyTimeoutLayer {
     type Service = RequestBodyTimeout<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        RequestBodyTimeout::new(inner, self.timeout)
+        RequestBodyTimeout::new(inner, self.timeouts)
+    }
+}
+
+/// Applies a [`TimeoutBody`] to the response body.
+#[derive(Clone, Debug)]
+pub struct ResponseBodyTimeout<S> {
+    inner: S,
+    timeouts: BodyTimeouts,
+}
+
+impl<S> ResponseBodyTimeout<S> {
+    /// Creates a new [`ResponseBodyTimeout`].
+    pub fn new(service: S, timeouts: BodyTimeouts) -> Self {
+        Self {
+            inner: service,
+            timeouts,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ResponseBodyTimeout<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Response = Response<TimeoutBody<ResBody>>;
+    type Error = S::Error;
+    type Future = ResponseBodyTimeoutFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        ResponseBodyTimeoutFuture {
+            future: self.inner.call(req),
+            timeouts: self.timeouts,
+        }
+    }
+}
+
+#[pin_project]
+pub struct ResponseBodyTimeoutFuture<F> {
+    #[pin]
+    future: F,
+    timeouts: BodyTimeouts,
+}
+
+impl<F, B, E> Future for ResponseBodyTimeoutFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<TimeoutBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = ready!(this.future.poll(cx))?;
+        let timeouts = *this.timeouts;
+        Poll::Ready(Ok(response.map(|body| TimeoutBody::new(timeouts, body))))
+    }
+}
+
+/// Applies a [`TimeoutBody`] to the response body.
+#[derive(Clone, Debug)]
+pub struct ResponseBodyTimeoutLayer {
+    timeouts: BodyTimeouts,
+}
+
+impl ResponseBodyTimeoutLayer {
+    /// Creates a new [`ResponseBodyTimeoutLayer`].
+    pub fn new(timeouts: BodyTimeouts) -> Self {
+        Self { timeouts }
+    }
+}
+
+impl<S> Layer<S> for ResponseBodyTimeoutLayer {
+    type Service = ResponseBodyTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseBodyTimeout::new(inner, self.timeouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, Stream, StreamExt};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::{Bytes, Frame};
+
+    use super::*;
+
+    type Chunks = std::pin::Pin<Box<dyn Stream<Item = Result<Frame<Bytes>, BoxError>> + Send>>;
+
+    /// Body sending `size` bytes every `period`
+    fn periodic(period: Duration, size: usize) -> TimeoutBody<StreamBody<Chunks>> {
+        periodic_with(
+            BodyTimeouts {
+                idle: Duration::from_secs(1),
+                min_throughput: None,
+                deadline: None,
+            },
+            period,
+            size,
+        )
+    }
+
+    fn periodic_with(
+        timeouts: BodyTimeouts,
+        period: Duration,
+        size: usize,
+    ) -> TimeoutBody<StreamBody<Chunks>> {
+        let chunks = stream::repeat(()).then(move |()| async move {
+            tokio::time::sleep(period).await;
+            Ok(Frame::data(Bytes::from(vec![b'x'; size])))
+        });
+        TimeoutBody::new(timeouts, StreamBody::new(Box::pin(chunks) as Chunks))
+    }
+
+    /// Reads the body until it fails
+    async fn tripped<B>(body: &mut B) -> TimeoutError
+    where
+        B: Body<Error = BoxError> + Unpin,
+    {
+        loop {
+            if let Err(err) = body.frame().await.unwrap() {
+                let err = err.downcast::<std::io::Error>().unwrap();
+                assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+                return *err.into_inner().unwrap().downcast().unwrap();
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle() {
+        let mut body = periodic(Duration::from_secs(2), 1);
+        let err = tripped(&mut body).await;
+        assert_eq!(err, TimeoutError::Idle(Duration::from_secs(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline() {
+        let mut body = periodic_with(
+            BodyTimeouts {
+                idle: Duration::from_secs(1),
+                min_throughput: None,
+                deadline: Some(Duration::from_secs(5)),
+            },
+            Duration::from_millis(500),
+            1,
+        );
+        let start = Instant::now();
+        let err = tripped(&mut body).await;
+        assert_eq!(err, TimeoutError::Deadline(Duration::from_secs(5)));
+        assert_eq!(start.elapsed().as_secs(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput() {
+        let min = MinThroughput {
+            bytes_per_second: 100,
+            window: Duration::from_secs(2),
+        };
+        let timeouts = BodyTimeouts {
+            idle: Duration::from_secs(1),
+            min_throughput: Some(min),
+            deadline: None,
+        };
+
+        // 200 bytes per second
+        let mut body = periodic_with(timeouts, Duration::from_millis(500), 100);
+        for _ in 0..20 {
+            body.frame().await.unwrap().unwrap();
+        }
+
+        // 20 bytes per second
+        let mut body = periodic_with(timeouts, Duration::from_millis(500), 10);
+        let err = tripped(&mut body).await;
+        assert_eq!(err, TimeoutError::Throughput(min));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_consumer() {
+        let min = MinThroughput {
+            bytes_per_second: 100,
+            window: Duration::from_secs(2),
+        };
+        let timeouts = BodyTimeouts {
+            idle: Duration::from_secs(1),
+            min_throughput: Some(min),
+            deadline: None,
+        };
+
+        // The body is fast, its consumer is slow
+        let mut body = periodic_with(timeouts, Duration::from_millis(100), 100);
+        for _ in 0..10 {
+            body.frame().await.unwrap().unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
     }
 }